@@ -1,197 +1,153 @@
-use std::{cmp::Ordering, collections::BinaryHeap};
-
 use bevy::math::Vec2;
 
-use super::pathfinding::{Pathfinding, PathfindingGraphConnection, PathfindingGraphNode};
-
-pub fn find_path(pathfinding: &Pathfinding, start_position: Vec2) -> Option<Vec<PathNode>> {
-    if pathfinding.goal_graph_node.is_none() {
-        return None;
-    }
-
-    let goal_node = pathfinding.goal_graph_node.as_ref().unwrap();
-
-    let mut open_list: BinaryHeap<AStarNode> = BinaryHeap::new();
-    let mut closed_list: Vec<AStarNode> = vec![];
-
-    // Get the start node
-    let start_node = get_start_node(pathfinding, start_position);
-
-    // Add the start node to the open list
-    open_list.push(start_node);
-
-    loop {
-        // If the open list is empty, there is no path
-        if open_list.is_empty() {
-            return None;
-        }
-
-        // Get the node with the lowest f-cost
-        let current_node = open_list.pop().unwrap();
-
-        // If the current node is the goal, reconstruct the path
-        if current_node.id == goal_node.id {
-            let mut path: Vec<PathNode> = vec![];
-
-            let mut current_node = current_node;
-            while let Some(parent_id) = current_node.parent {
-                let parent_node = closed_list.iter().find(|n| n.id == parent_id).unwrap();
-                path.push(PathNode::new(parent_id, parent_node.position));
-                current_node = parent_node.clone();
-            }
-
-            path.reverse();
-
-            return Some(path);
-        }
-
-        // If the node is in the closed list, skip it
-        if closed_list.iter().any(|n| n.id == current_node.id) {
-            continue;
-        }
-
-        // Add the current node to the closed list
-        closed_list.push(current_node.clone());
-
-        // For each connection of the current node
-        for connection in current_node.connections.iter() {
-            let connected_graph_node = &pathfinding.nodes[connection.node_id];
-            let mut new_node = AStarNode::new(connected_graph_node);
-
-            // If the new node is the goal, set the is_goal flag
-            if new_node.id != goal_node.id {
-                // Set the g-cost to the distance to the start node
-                new_node.g_cost = connection.dist + current_node.g_cost;
-
-                // Set the h-cost to the distance to the goal
-                new_node.h_cost = (pathfinding.goal_position - new_node.position).length();
-            }
-
-            // Set the parent of the new node
-            new_node.parent = Some(current_node.id);
-
-            open_list.push(new_node);
-        }
-    }
+use crate::level::Level;
+
+use super::pathfinding::PathfindingGraph;
+
+/// How many nearby nodes the temporary start and goal nodes are connected to
+const TEMPORARY_NODE_CONNECTION_COUNT: usize = 2;
+
+/// The nodes that an agent at `position` could get to straight away, or get to `position` from.
+/// These are the closest nodes that have a clear line to the position from where the agent stands
+/// on them, and that the position is on the open side of the surface of.
+/// A position that no node can see, like one inside of a wall, gets the closest node instead.
+pub fn get_reachable_nodes(
+    pathfinding: &PathfindingGraph,
+    level: &Level,
+    position: Vec2,
+) -> Vec<usize> {
+    let mut reachable_nodes = Vec::new();
+
+    fill_reachable_nodes(
+        pathfinding,
+        level,
+        position,
+        &mut Vec::new(),
+        &mut reachable_nodes,
+    );
+
+    reachable_nodes
 }
 
-fn get_start_node(pathfinding: &Pathfinding, start_position: Vec2) -> AStarNode {
-    let mut start_graph_node: PathfindingGraphNode = PathfindingGraphNode {
-        id: 0,
-        position: Vec2::ZERO,
-        polygon_index: 0,
-        line_indicies: vec![],
-        walkable_connections: vec![],
-        jumpable_connections: vec![],
-        droppable_connections: vec![],
-        normal: Vec2::ZERO,
-        is_corner: false,
-        is_external_corner: None,
-    };
-    let mut start_graph_node_distance = f32::MAX;
-
-    for node in pathfinding.nodes.iter() {
-        let distance = (start_position - node.position).length_squared();
-
-        if distance > start_graph_node_distance {
-            continue;
-        }
-
-        if distance == start_graph_node_distance {
-            let start_node_to_goal = (pathfinding.goal_position - start_position).length_squared();
-            let current_node_to_goal = (pathfinding.goal_position - node.position).length_squared();
-
-            if current_node_to_goal > start_node_to_goal {
-                continue;
-            }
-        }
-
-        start_graph_node_distance = distance;
-        start_graph_node = node.clone();
+/// `get_reachable_nodes`, into buffers that are kept between searches
+pub fn fill_reachable_nodes(
+    pathfinding: &PathfindingGraph,
+    level: &Level,
+    position: Vec2,
+    nodes_by_distance: &mut Vec<(usize, f32)>,
+    reachable_nodes: &mut Vec<usize>,
+) {
+    nodes_by_distance.clear();
+    nodes_by_distance.extend(
+        pathfinding
+            .nodes
+            .iter()
+            .map(|node| (node.id, (position - node.position).length_squared())),
+    );
+    nodes_by_distance.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    reachable_nodes.clear();
+    reachable_nodes.extend(
+        nodes_by_distance
+            .iter()
+            .map(|(node_id, _)| &pathfinding.nodes[*node_id])
+            .filter(|node| {
+                // Nodes the position is behind the surface of, like on the other side of a thin wall
+                (position - node.position).dot(node.normal) >= 0.0
+            })
+            .filter(|node| {
+                let agent_position = node.position + node.normal * pathfinding.profile.radius;
+
+                level.line_of_sight_check(agent_position, position)
+            })
+            .map(|node| node.id)
+            .take(TEMPORARY_NODE_CONNECTION_COUNT),
+    );
+
+    if reachable_nodes.is_empty() {
+        reachable_nodes.extend(nodes_by_distance.first().map(|(node_id, _)| *node_id));
     }
-
-    let mut start_a_star_node = AStarNode::new(&start_graph_node);
-
-    // Set the h-cost to the distance to the goal
-    start_a_star_node.h_cost = (pathfinding.goal_position - start_a_star_node.position).length();
-
-    return start_a_star_node;
 }
 
-#[derive(Clone, Debug)]
-pub struct AStarNode {
+pub struct PathNode {
+    /// The node of the graph, or nothing for the goal position at the end of the path
+    pub id: Option<usize>,
     pub position: Vec2,
-    pub id: usize,
-    pub connections: Vec<PathfindingGraphConnection>,
-    pub g_cost: f32,
-    pub h_cost: f32,
-    pub parent: Option<usize>,
-    pub is_corner: bool,
-    pub is_external_corner: Option<bool>,
-}
-
-impl AStarNode {
-    pub fn new(graph_node: &PathfindingGraphNode) -> AStarNode {
-        let connections = [
-            graph_node.walkable_connections.as_slice(),
-            graph_node.jumpable_connections.as_slice(),
-        ]
-        .concat();
-
-        AStarNode {
-            position: graph_node.position,
-            id: graph_node.id,
-            connections,
-            g_cost: 0.0,
-            h_cost: 0.0,
-            parent: None,
-            is_corner: graph_node.is_corner,
-            is_external_corner: graph_node.is_external_corner,
-        }
-    }
-
-    pub fn get_f_cost(&self) -> f32 {
-        self.g_cost + self.h_cost
-    }
-}
-
-impl Ord for AStarNode {
-    fn cmp(&self, other: &Self) -> Ordering {
-        let self_f_cost = self.get_f_cost();
-        let other_f_cost = other.get_f_cost();
-
-        match self_f_cost.partial_cmp(&other_f_cost) {
-            Some(Ordering::Equal) => self
-                .h_cost
-                .partial_cmp(&other.h_cost)
-                .unwrap_or(Ordering::Equal),
-            Some(order) => order.reverse(),
-            None => Ordering::Equal,
-        }
-    }
 }
 
-impl Eq for AStarNode {}
-
-impl PartialOrd for AStarNode {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl PartialEq for AStarNode {
-    fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
+impl PathNode {
+    pub fn new(id: Option<usize>, position: Vec2) -> PathNode {
+        PathNode { id, position }
     }
 }
 
-pub struct PathNode {
-    pub id: usize,
-    pub position: Vec2,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ai::{
+            pathfinding::{build_pathfinding_graph, NodePlacementSettings},
+            platformer_ai::DEFAULT_AGENT_PROFILE,
+            reference_search::{find_path, PathSearch},
+        },
+        level::loader::PolygonData,
+    };
 
-impl PathNode {
-    pub fn new(id: usize, position: Vec2) -> PathNode {
-        PathNode { id, position }
+    #[test]
+    fn goal_under_a_thin_platform_is_reached_from_the_floor() {
+        // A room with a thin platform floating in the middle of it
+        let level = Level::from_polygons(
+            &[
+                PolygonData {
+                    points: vec![
+                        Vec2::new(-200.0, -50.0),
+                        Vec2::new(200.0, -50.0),
+                        Vec2::new(200.0, 150.0),
+                        Vec2::new(-200.0, 150.0),
+                    ],
+                    is_container: true,
+                },
+                PolygonData {
+                    points: vec![
+                        Vec2::new(-50.0, 0.0),
+                        Vec2::new(50.0, 0.0),
+                        Vec2::new(50.0, 4.0),
+                        Vec2::new(-50.0, 4.0),
+                    ],
+                    is_container: false,
+                },
+            ],
+            32.0,
+        )
+        .unwrap();
+
+        let mut pathfinding = PathfindingGraph::new(&DEFAULT_AGENT_PROFILE);
+        build_pathfinding_graph(&level, &mut pathfinding, &NodePlacementSettings::default());
+
+        // Right under the platform, where the closest node is on top of it
+        let goal_position = Vec2::new(0.0, -6.0);
+        let closest_node = pathfinding.get_closest_node(goal_position).unwrap();
+        assert_eq!(pathfinding.nodes[closest_node].position.y, 4.0);
+
+        let reachable_nodes = get_reachable_nodes(&pathfinding, &level, goal_position);
+        assert!(!reachable_nodes.is_empty());
+        assert!(reachable_nodes
+            .iter()
+            .all(|node_id| pathfinding.nodes[*node_id].position.y == -50.0));
+
+        // So the path stays on the floor instead of going up onto the platform
+        let path = find_path(
+            &mut PathSearch::default(),
+            &pathfinding,
+            &level,
+            goal_position,
+            Vec2::new(-150.0, -42.0),
+        )
+        .unwrap();
+        assert!(path.len() > 1);
+        assert!(path
+            .iter()
+            .filter(|path_node| path_node.id.is_some())
+            .all(|path_node| path_node.position.y == -50.0));
     }
 }
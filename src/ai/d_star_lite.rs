@@ -0,0 +1,626 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use bevy::math::Vec2;
+
+use crate::level::Level;
+
+use super::{
+    a_star::{fill_reachable_nodes, PathNode},
+    pathfinding::PathfindingGraph,
+};
+
+/// An agent's path search, kept between frames and repaired as the agent and the goal move,
+/// instead of searched again from scratch.
+/// It's a D* Lite search, which works backwards from the goal, so its costs are to the goal.
+/// Like the A* search in `reference_search`, which it's checked against in the tests, the start
+/// and goal are temporary nodes that come after the graph's nodes.
+#[derive(Default)]
+pub struct PathPlanner {
+    /// The cache key of the graph the search is over, so it can be started over when the graph
+    /// is rebuilt from something else
+    graph_cache_key: Option<u64>,
+    /// The cost of getting from each node to the goal, as of when it was last expanded
+    g_costs: Vec<f32>,
+    /// The cost of getting from each node to the goal through its cheapest connection.
+    /// Nodes where this doesn't match the g-cost have to be expanded again.
+    rhs_costs: Vec<f32>,
+    /// The key each node is in the open list with, any other entries for it are out of date
+    keys: Vec<Option<Key>>,
+    open_list: BinaryHeap<OpenNode>,
+    /// The connections into each node of the graph, which are what a change to its cost affects
+    predecessors: Vec<Vec<usize>>,
+    /// How far the start has moved since the search began. It's added to every key,
+    /// instead of updating the heuristic of every node in the open list.
+    key_modifier: f32,
+    /// Where the search starts and ends. Moving the goal changes the cost of the nodes connected
+    /// to it, and every node's cost through them, while the start is cheap to move.
+    start_position: Vec2,
+    goal_position: Vec2,
+    start_connections: Vec<usize>,
+    goal_connections: Vec<usize>,
+    /// Where the start and goal were asked for last time.
+    /// If they haven't moved at all, their connections don't have to be looked for again.
+    last_start_position: Option<Vec2>,
+    last_goal_position: Option<Vec2>,
+    /// The nodes connected to the node being looked at, and what the connections cost
+    neighbours: Vec<(usize, f32)>,
+    /// The nodes connected into the node being expanded
+    expanded_predecessors: Vec<usize>,
+    nodes_by_distance: Vec<(usize, f32)>,
+    reachable_nodes: Vec<usize>,
+}
+
+impl PathPlanner {
+    /// Finds the cheapest path from the start position to the goal position, reusing as much of
+    /// the last search as it can. The path is made of the graph nodes between them, followed by
+    /// the goal position.
+    pub fn plan_path(
+        &mut self,
+        pathfinding: &PathfindingGraph,
+        level: &Level,
+        goal_position: Vec2,
+        start_position: Vec2,
+    ) -> Option<Vec<PathNode>> {
+        let node_count = pathfinding.nodes.len();
+        let start_id = node_count;
+        let goal_id = node_count + 1;
+
+        if node_count == 0 {
+            return None;
+        }
+
+        // The first search, or the graph was rebuilt since the last one
+        if self.graph_cache_key != Some(pathfinding.cache_key) {
+            self.reset(pathfinding, goal_position, start_position);
+        }
+
+        if self.last_goal_position != Some(goal_position) {
+            self.last_goal_position = Some(goal_position);
+
+            fill_reachable_nodes(
+                pathfinding,
+                level,
+                goal_position,
+                &mut self.nodes_by_distance,
+                &mut self.reachable_nodes,
+            );
+
+            // They're closest first, which changes as soon as the goal moves past the middle
+            // of two nodes, but only which nodes they are matters
+            self.reachable_nodes.sort_unstable();
+
+            self.goal_position = goal_position;
+
+            // The goal moved onto different nodes, so the connections into it changed
+            if self.reachable_nodes != self.goal_connections {
+                std::mem::swap(&mut self.goal_connections, &mut self.reachable_nodes);
+
+                // Both the nodes that lost their connection to the goal and the ones that got one
+                let mut changed_nodes = std::mem::take(&mut self.reachable_nodes);
+                changed_nodes.extend_from_slice(&self.goal_connections);
+
+                for node_id in &changed_nodes {
+                    self.update_node(pathfinding, *node_id);
+                }
+
+                self.reachable_nodes = changed_nodes;
+            } else {
+                // Still the same nodes, but what their connections to the goal cost changed
+                for index in 0..self.goal_connections.len() {
+                    self.update_node(pathfinding, self.goal_connections[index]);
+                }
+            }
+        }
+
+        if self.last_start_position != Some(start_position) {
+            self.last_start_position = Some(start_position);
+
+            fill_reachable_nodes(
+                pathfinding,
+                level,
+                start_position,
+                &mut self.nodes_by_distance,
+                &mut self.reachable_nodes,
+            );
+
+            std::mem::swap(&mut self.start_connections, &mut self.reachable_nodes);
+
+            // Moving the start only changes the connections out of it, which is cheap to repair.
+            // The heuristic is the distance from the start, which is now off by up to this much.
+            self.key_modifier += (start_position - self.start_position).length();
+            self.start_position = start_position;
+
+            self.update_node(pathfinding, start_id);
+        }
+
+        self.repair(pathfinding);
+
+        if !self.g_costs[start_id].is_finite() {
+            return None;
+        }
+
+        // Follow the cheapest connections from the start to the goal
+        let mut path: Vec<PathNode> = vec![];
+        let mut neighbours = std::mem::take(&mut self.neighbours);
+
+        let mut current_id = start_id;
+        while current_id != goal_id {
+            // Every step gets cheaper, so the path can't be longer than the graph
+            if path.len() > node_count {
+                break;
+            }
+
+            self.fill_successors(pathfinding, current_id, &mut neighbours);
+
+            let next_node = neighbours
+                .iter()
+                .filter(|(node_id, _)| *node_id != current_id)
+                .map(|(node_id, dist)| (*node_id, dist + self.g_costs[*node_id]))
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+
+            let Some((next_id, _)) = next_node.filter(|(_, cost)| cost.is_finite()) else {
+                break;
+            };
+
+            if next_id != goal_id {
+                path.push(PathNode::new(
+                    Some(next_id),
+                    pathfinding.nodes[next_id].position,
+                ));
+            }
+
+            current_id = next_id;
+        }
+
+        self.neighbours = neighbours;
+
+        if current_id != goal_id {
+            return None;
+        }
+
+        path.push(PathNode::new(None, goal_position));
+
+        Some(path)
+    }
+
+    /// Throws away the last search and starts a new one from the goal
+    fn reset(&mut self, pathfinding: &PathfindingGraph, goal_position: Vec2, start_position: Vec2) {
+        let node_count = pathfinding.nodes.len();
+        let goal_id = node_count + 1;
+
+        self.graph_cache_key = Some(pathfinding.cache_key);
+
+        self.g_costs.clear();
+        self.g_costs.resize(node_count + 2, f32::INFINITY);
+        self.rhs_costs.clear();
+        self.rhs_costs.resize(node_count + 2, f32::INFINITY);
+        self.keys.clear();
+        self.keys.resize(node_count + 2, None);
+        self.open_list.clear();
+
+        self.predecessors.clear();
+        self.predecessors.resize(node_count, Vec::new());
+
+        for node in &pathfinding.nodes {
+            for connection in node.connections() {
+                if !self.predecessors[connection.node_id].contains(&node.id) {
+                    self.predecessors[connection.node_id].push(node.id);
+                }
+            }
+        }
+
+        self.key_modifier = 0.0;
+        self.start_position = start_position;
+        self.goal_position = goal_position;
+        self.start_connections.clear();
+        self.goal_connections.clear();
+        self.last_start_position = None;
+        self.last_goal_position = None;
+
+        // The search spreads out from the goal
+        self.rhs_costs[goal_id] = 0.0;
+        self.update_node(pathfinding, goal_id);
+    }
+
+    /// Expands nodes until the cost from the start to the goal is known
+    fn repair(&mut self, pathfinding: &PathfindingGraph) {
+        let start_id = pathfinding.nodes.len();
+
+        let mut predecessors = std::mem::take(&mut self.expanded_predecessors);
+
+        while let Some(current_node) = self.open_list.peek() {
+            let current_id = current_node.id;
+            let current_key = current_node.key;
+
+            // The node was updated since this entry was added
+            if self.keys[current_id] != Some(current_key) {
+                self.open_list.pop();
+                continue;
+            }
+
+            // Nothing left in the open list could make the path from the start any cheaper
+            if current_key.compare(&self.get_key(pathfinding, start_id)) != Ordering::Less
+                && self.rhs_costs[start_id] == self.g_costs[start_id]
+            {
+                break;
+            }
+
+            self.open_list.pop();
+            self.keys[current_id] = None;
+
+            let key = self.get_key(pathfinding, current_id);
+
+            // The start moved since the node was added, so it has to wait its turn again
+            if current_key.compare(&key) == Ordering::Less {
+                self.push(current_id, key);
+                continue;
+            }
+
+            if self.g_costs[current_id] > self.rhs_costs[current_id] {
+                // A cheaper way to the goal was found
+                self.g_costs[current_id] = self.rhs_costs[current_id];
+            } else {
+                // The way to the goal got more expensive, so it has to be found again
+                self.g_costs[current_id] = f32::INFINITY;
+                self.update_node(pathfinding, current_id);
+            }
+
+            // The nodes that lead into this one can get to the goal through it
+            self.fill_predecessors(pathfinding, current_id, &mut predecessors);
+
+            for node_id in predecessors.iter() {
+                self.update_node(pathfinding, *node_id);
+            }
+        }
+
+        self.expanded_predecessors = predecessors;
+    }
+
+    /// Works out a node's cost through its cheapest connection,
+    /// and puts it in the open list if that changed its cost
+    fn update_node(&mut self, pathfinding: &PathfindingGraph, node_id: usize) {
+        let goal_id = pathfinding.nodes.len() + 1;
+
+        if node_id != goal_id {
+            let mut neighbours = std::mem::take(&mut self.neighbours);
+            self.fill_successors(pathfinding, node_id, &mut neighbours);
+
+            self.rhs_costs[node_id] = neighbours
+                .iter()
+                .map(|(other_id, dist)| dist + self.g_costs[*other_id])
+                .fold(f32::INFINITY, f32::min);
+
+            self.neighbours = neighbours;
+        }
+
+        if self.g_costs[node_id] != self.rhs_costs[node_id] {
+            let key = self.get_key(pathfinding, node_id);
+            self.push(node_id, key);
+        } else {
+            self.keys[node_id] = None;
+        }
+    }
+
+    fn push(&mut self, node_id: usize, key: Key) {
+        self.keys[node_id] = Some(key);
+        self.open_list.push(OpenNode { id: node_id, key });
+    }
+
+    /// Nodes are expanded in order of their cost to the goal plus their distance from the start
+    fn get_key(&self, pathfinding: &PathfindingGraph, node_id: usize) -> Key {
+        let cost = self.g_costs[node_id].min(self.rhs_costs[node_id]);
+        let h_cost = (self.get_position(pathfinding, node_id) - self.start_position).length();
+
+        Key(cost + h_cost + self.key_modifier, cost)
+    }
+
+    fn get_position(&self, pathfinding: &PathfindingGraph, node_id: usize) -> Vec2 {
+        match node_id.checked_sub(pathfinding.nodes.len()) {
+            Some(0) => self.start_position,
+            Some(_) => self.goal_position,
+            None => pathfinding.nodes[node_id].position,
+        }
+    }
+
+    /// The nodes a node is connected to, including the temporary nodes
+    fn fill_successors(
+        &self,
+        pathfinding: &PathfindingGraph,
+        node_id: usize,
+        successors: &mut Vec<(usize, f32)>,
+    ) {
+        let nodes = &pathfinding.nodes;
+        let start_id = nodes.len();
+        let goal_id = nodes.len() + 1;
+
+        successors.clear();
+
+        if node_id == goal_id {
+            return;
+        }
+
+        if node_id == start_id {
+            successors.extend(self.start_connections.iter().map(|other_id| {
+                (
+                    *other_id,
+                    (nodes[*other_id].position - self.start_position).length(),
+                )
+            }));
+
+            return;
+        }
+
+        let node = &nodes[node_id];
+
+        successors.extend(
+            node.connections()
+                .map(|connection| (connection.node_id, connection.dist)),
+        );
+
+        if self.goal_connections.contains(&node_id) {
+            successors.push((goal_id, (self.goal_position - node.position).length()));
+        }
+    }
+
+    /// The nodes that are connected to a node, including the temporary nodes
+    fn fill_predecessors(
+        &self,
+        pathfinding: &PathfindingGraph,
+        node_id: usize,
+        predecessors: &mut Vec<usize>,
+    ) {
+        let start_id = pathfinding.nodes.len();
+        let goal_id = pathfinding.nodes.len() + 1;
+
+        predecessors.clear();
+
+        if node_id == goal_id {
+            predecessors.extend_from_slice(&self.goal_connections);
+            return;
+        }
+
+        if node_id == start_id {
+            return;
+        }
+
+        predecessors.extend_from_slice(&self.predecessors[node_id]);
+
+        if self.start_connections.contains(&node_id) {
+            predecessors.push(start_id);
+        }
+    }
+}
+
+/// The order nodes are expanded in: by their estimated cost of a path from the start
+/// through them, then by their cost to the goal
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Key(f32, f32);
+
+impl Key {
+    fn compare(&self, other: &Key) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.total_cmp(&other.1))
+    }
+}
+
+/// A node in the open list, waiting to be expanded
+struct OpenNode {
+    id: usize,
+    key: Key,
+}
+
+impl Ord for OpenNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.compare(&other.key).reverse()
+    }
+}
+
+impl Eq for OpenNode {}
+
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OpenNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::ai::{
+        pathfinding::{build_pathfinding_graph, NodePlacementSettings},
+        platformer_ai::DEFAULT_AGENT_PROFILE,
+        reference_search::{find_path, PathSearch},
+        test_utils::{for_each_shipped_graph, get_level, get_path_cost},
+    };
+
+    /// Where an agent stands next to every few nodes
+    fn get_agent_positions(pathfinding: &PathfindingGraph, step: usize) -> Vec<Vec2> {
+        pathfinding
+            .nodes
+            .iter()
+            .step_by(step)
+            .map(|node| node.position + node.normal * pathfinding.profile.radius)
+            .collect()
+    }
+
+    /// The positions of something moving from point to point, `speed` pixels a frame.
+    /// It jumps straight to points it can't see, instead of going through the walls.
+    fn get_positions_along(level: &Level, points: &[Vec2], speed: f32) -> Vec<Vec2> {
+        let mut positions = vec![];
+
+        for segment in points.windows(2) {
+            let steps = if level.line_of_sight_check(segment[0], segment[1]) {
+                ((segment[1] - segment[0]).length() / speed).ceil().max(1.0) as usize
+            } else {
+                1
+            };
+
+            for step in 0..steps {
+                positions.push(segment[0].lerp(segment[1], step as f32 / steps as f32));
+            }
+        }
+
+        positions
+    }
+
+    #[test]
+    fn plan_path_is_as_cheap_as_find_path_while_moving() {
+        let mut path_search = PathSearch::default();
+
+        for_each_shipped_graph(|level, pathfinding| {
+            // The goal and the agent go over the level in opposite directions
+            let mut route = get_agent_positions(pathfinding, 5);
+            let goal_positions = get_positions_along(level, &route, 8.0);
+            route.reverse();
+            let start_positions = get_positions_along(level, &route, 5.0);
+
+            let mut path_planner = PathPlanner::default();
+
+            for (goal_position, start_position) in goal_positions.into_iter().zip(start_positions) {
+                let path =
+                    path_planner.plan_path(pathfinding, level, goal_position, start_position);
+                let expected_path = find_path(
+                    &mut path_search,
+                    pathfinding,
+                    level,
+                    goal_position,
+                    start_position,
+                );
+
+                match (path, expected_path) {
+                    (Some(path), Some(expected_path)) => {
+                        let cost =
+                            get_path_cost(pathfinding, level, start_position, goal_position, &path);
+                        let expected_cost = get_path_cost(
+                            pathfinding,
+                            level,
+                            start_position,
+                            goal_position,
+                            &expected_path,
+                        );
+
+                        let tolerance = expected_cost * 1e-5 + 1e-3;
+
+                        assert!(
+                            (cost - expected_cost).abs() <= tolerance,
+                            "from {} to {}: the path costs {}, but the cheapest costs {}",
+                            start_position,
+                            goal_position,
+                            cost,
+                            expected_cost
+                        );
+                    }
+                    (None, None) => {}
+                    (path, expected_path) => panic!(
+                        "from {} to {}: planned a path: {}, found a path: {}",
+                        start_position,
+                        goal_position,
+                        path.is_some(),
+                        expected_path.is_some()
+                    ),
+                }
+            }
+        });
+    }
+
+    /// Hunters spread out over the level chase the goal as it goes through its positions,
+    /// one frame each. Times planning their paths against searching from scratch.
+    fn bench_chase(
+        pathfinding: &PathfindingGraph,
+        level: &Level,
+        goal_positions: &[Vec2],
+        description: &str,
+    ) {
+        let mut hunter_positions = get_agent_positions(pathfinding, 23);
+        let mut path_planners: Vec<PathPlanner> = hunter_positions
+            .iter()
+            .map(|_| PathPlanner::default())
+            .collect();
+
+        let mut queries: Vec<(Vec2, Vec2)> = vec![];
+        let mut planner_time = Duration::ZERO;
+
+        for goal_position in goal_positions {
+            for (hunter_position, path_planner) in
+                hunter_positions.iter_mut().zip(&mut path_planners)
+            {
+                queries.push((*hunter_position, *goal_position));
+
+                let start = Instant::now();
+                let path =
+                    path_planner.plan_path(pathfinding, level, *goal_position, *hunter_position);
+                planner_time += start.elapsed();
+
+                // Head a few pixels toward the next point of the path
+                if let Some(next_position) =
+                    path.and_then(|path| path.first().map(|path_node| path_node.position))
+                {
+                    *hunter_position += (next_position - *hunter_position).clamp_length_max(3.0);
+                }
+            }
+        }
+
+        let mut path_search = PathSearch::default();
+
+        let start = Instant::now();
+        for (start_position, goal_position) in &queries {
+            find_path(
+                &mut path_search,
+                pathfinding,
+                level,
+                *goal_position,
+                *start_position,
+            );
+        }
+        let find_path_time = start.elapsed();
+
+        println!(
+            "{}, {} hunters over {} frames: searching from scratch took {:?} per query, planning took {:?} per query ({:.1}x)",
+            description,
+            hunter_positions.len(),
+            goal_positions.len(),
+            find_path_time / queries.len() as u32,
+            planner_time / queries.len() as u32,
+            find_path_time.as_secs_f64() / planner_time.as_secs_f64()
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_plan_path() {
+        let level = get_level(include_bytes!("../../assets/level.json"));
+
+        let mut pathfinding = PathfindingGraph::new(&DEFAULT_AGENT_PROFILE);
+        build_pathfinding_graph(&level, &mut pathfinding, &NodePlacementSettings::default());
+
+        let route = get_agent_positions(&pathfinding, 3);
+
+        bench_chase(
+            &pathfinding,
+            &level,
+            &get_positions_along(&level, &route, 4.0),
+            "Goal always moving",
+        );
+
+        // Waits two seconds at every few points of the route, then jumps to the next one
+        let waiting_goal_positions: Vec<Vec2> = route
+            .iter()
+            .step_by(8)
+            .flat_map(|position| std::iter::repeat_n(*position, 120))
+            .collect();
+
+        bench_chase(
+            &pathfinding,
+            &level,
+            &waiting_goal_positions,
+            "Goal waiting",
+        );
+    }
+}
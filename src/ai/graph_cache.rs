@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    hash::Hasher,
+    path::{Path, PathBuf},
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use bevy::asset::io::file::FileAssetReader;
+use bevy::log::error;
+
+use crate::level::Level;
+
+use super::{
+    pathfinding::{NodePlacementSettings, PathfindingGraphNode},
+    platformer_ai::AgentProfile,
+};
+
+/// Where the last built graphs are kept, next to the assets folder
+const GRAPH_CACHE_FILE_NAME: &str = "pathfinding_graph.cache.json";
+
+/// Bump this whenever the way the graph is built changes, so old caches aren't used
+const GRAPH_CACHE_VERSION: u32 = 8;
+
+/// The nodes of each cached graph, by their key
+pub type GraphCache = HashMap<u64, Vec<PathfindingGraphNode>>;
+
+/// A hash of everything a graph is built from: the level geometry and shortcuts,
+/// the agent's profile and the node placement settings
+pub fn get_graph_cache_key(
+    level: &Level,
+    profile: &AgentProfile,
+    node_placement_settings: &NodePlacementSettings,
+) -> u64 {
+    let mut hasher = Fnv1aHasher::default();
+
+    hasher.write_u32(GRAPH_CACHE_VERSION);
+    hasher.write_u32(profile.radius.to_bits());
+    hasher.write_u32(profile.jump_force.to_bits());
+    hasher.write_u32(profile.gravity.to_bits());
+    hasher.write_u32(profile.node_spacing.to_bits());
+    hasher.write_u8(profile.can_crawl as u8);
+
+    hasher.write_u32(node_placement_settings.spacing_scale.to_bits());
+    hasher.write_u8(node_placement_settings.floors as u8);
+    hasher.write_u8(node_placement_settings.walls as u8);
+    hasher.write_u8(node_placement_settings.ceilings as u8);
+    hasher.write_u8(node_placement_settings.always_place_corner_nodes as u8);
+
+    hasher.write_u64(level.polygons.len() as u64);
+
+    for polygon in &level.polygons {
+        hasher.write_u8(polygon.is_container as u8);
+        hasher.write_u64(polygon.points.len() as u64);
+
+        for point in &polygon.points {
+            hasher.write_u32(point.x.to_bits());
+            hasher.write_u32(point.y.to_bits());
+        }
+    }
+
+    hasher.write_u64(level.shortcuts.len() as u64);
+
+    for shortcut in &level.shortcuts {
+        hasher.write_u64(shortcut.entrances.len() as u64);
+
+        for entrance in &shortcut.entrances {
+            hasher.write_u32(entrance.x.to_bits());
+            hasher.write_u32(entrance.y.to_bits());
+        }
+    }
+
+    hasher.finish()
+}
+
+/// Reads the cached graphs, or nothing if there aren't any
+pub fn load_graph_cache() -> GraphCache {
+    let Some(file_path) = graph_cache_file() else {
+        return GraphCache::new();
+    };
+
+    load_graph_cache_from(&file_path)
+}
+
+fn load_graph_cache_from(file_path: &Path) -> GraphCache {
+    let Ok(bytes) = std::fs::read(file_path) else {
+        return GraphCache::new();
+    };
+
+    match serde_json::from_slice(&bytes) {
+        Ok(graph_cache) => graph_cache,
+        Err(error) => {
+            error!("Could not read the pathfinding graph cache: {}", error);
+            GraphCache::new()
+        }
+    }
+}
+
+/// Writes every graph in `graph_cache` over the cache file,
+/// so it should include the graphs that were loaded from it
+pub fn save_graph_cache(graph_cache: &GraphCache) {
+    let Some(file_path) = graph_cache_file() else {
+        return;
+    };
+
+    save_graph_cache_to(&file_path, graph_cache);
+}
+
+fn save_graph_cache_to(file_path: &Path, graph_cache: &GraphCache) {
+    let result = serde_json::to_vec(graph_cache)
+        .map_err(|error| error.to_string())
+        .and_then(|bytes| std::fs::write(file_path, bytes).map_err(|error| error.to_string()));
+
+    if let Err(error) = result {
+        error!(
+            "Could not write the pathfinding graph cache to {}: {}",
+            file_path.display(),
+            error
+        );
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn graph_cache_file() -> Option<PathBuf> {
+    Some(FileAssetReader::get_base_path().join(GRAPH_CACHE_FILE_NAME))
+}
+
+/// There is no filesystem on wasm, so the graph is always built
+#[cfg(target_arch = "wasm32")]
+fn graph_cache_file() -> Option<PathBuf> {
+    None
+}
+
+/// The std hasher can change between Rust versions, this one is the same everywhere
+struct Fnv1aHasher(u64);
+
+impl Default for Fnv1aHasher {
+    fn default() -> Self {
+        Fnv1aHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1aHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ai::{
+            pathfinding::{build_pathfinding_graph, PathfindingGraph},
+            platformer_ai::DEFAULT_AGENT_PROFILE,
+        },
+        level::tileset::Tileset,
+    };
+
+    fn get_test_level(level_grid_data: &[Vec<usize>]) -> Level {
+        let tileset =
+            Tileset::from_bytes(include_bytes!("../../assets/tiles.tileset.json")).unwrap();
+
+        Level::from_grid(level_grid_data, &tileset, 32.0).unwrap()
+    }
+
+    fn get_box_level_grid() -> Vec<Vec<usize>> {
+        vec![
+            vec![1, 1, 1, 1, 1],
+            vec![1, 0, 0, 0, 1],
+            vec![1, 0, 0, 0, 1],
+            vec![1, 1, 1, 1, 1],
+        ]
+    }
+
+    #[test]
+    fn key_changes_with_the_level_and_profile() {
+        let settings = NodePlacementSettings::default();
+        let level = get_test_level(&get_box_level_grid());
+        let key = get_graph_cache_key(&level, &DEFAULT_AGENT_PROFILE, &settings);
+
+        // The same inputs always give the same key
+        assert_eq!(
+            get_graph_cache_key(&level, &DEFAULT_AGENT_PROFILE, &settings),
+            key
+        );
+
+        let mut level_grid_data = get_box_level_grid();
+        level_grid_data[2][2] = 1;
+        let changed_level = get_test_level(&level_grid_data);
+
+        assert_ne!(
+            get_graph_cache_key(&changed_level, &DEFAULT_AGENT_PROFILE, &settings),
+            key
+        );
+
+        let changed_profiles = [
+            AgentProfile {
+                radius: DEFAULT_AGENT_PROFILE.radius + 1.0,
+                ..DEFAULT_AGENT_PROFILE
+            },
+            AgentProfile {
+                jump_force: DEFAULT_AGENT_PROFILE.jump_force + 1.0,
+                ..DEFAULT_AGENT_PROFILE
+            },
+            AgentProfile {
+                gravity: DEFAULT_AGENT_PROFILE.gravity + 0.1,
+                ..DEFAULT_AGENT_PROFILE
+            },
+            AgentProfile {
+                node_spacing: DEFAULT_AGENT_PROFILE.node_spacing + 1.0,
+                ..DEFAULT_AGENT_PROFILE
+            },
+            AgentProfile {
+                can_crawl: !DEFAULT_AGENT_PROFILE.can_crawl,
+                ..DEFAULT_AGENT_PROFILE
+            },
+        ];
+
+        for profile in changed_profiles {
+            assert_ne!(get_graph_cache_key(&level, &profile, &settings), key);
+        }
+    }
+
+    #[test]
+    fn graph_cache_survives_a_round_trip() {
+        let settings = NodePlacementSettings::default();
+        let level = get_test_level(&get_box_level_grid());
+
+        let mut graph = PathfindingGraph::new(&DEFAULT_AGENT_PROFILE);
+        build_pathfinding_graph(&level, &mut graph, &settings);
+
+        let mut graph_cache = GraphCache::new();
+        graph_cache.insert(
+            get_graph_cache_key(&level, &DEFAULT_AGENT_PROFILE, &settings),
+            graph.nodes,
+        );
+
+        let file_path = std::env::temp_dir().join(format!(
+            "pathfinding_graph_cache_test_{}.json",
+            std::process::id()
+        ));
+
+        save_graph_cache_to(&file_path, &graph_cache);
+        let loaded_graph_cache = load_graph_cache_from(&file_path);
+        std::fs::remove_file(&file_path).unwrap();
+
+        assert_eq!(loaded_graph_cache, graph_cache);
+    }
+}
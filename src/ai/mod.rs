@@ -1,3 +1,9 @@
 pub mod a_star;
+pub mod d_star_lite;
+pub mod graph_cache;
 pub mod pathfinding;
 pub mod platformer_ai;
+#[cfg(test)]
+pub mod reference_search;
+#[cfg(test)]
+pub mod test_utils;
@@ -1,52 +1,128 @@
+use std::collections::HashMap;
+
 use bevy::{
     app::{App, Plugin},
-    ecs::system::{ResMut, Resource},
+    ecs::system::Resource,
+    log::info,
     math::Vec2,
+    tasks::{ComputeTaskPool, ParallelSlice, TaskPool},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    level::{CircleCastHit, Level, PolygonLine},
+    utils::line_intersect,
+};
+
+use super::{
+    graph_cache::{get_graph_cache_key, load_graph_cache, save_graph_cache, GraphCache},
+    platformer_ai::{AgentProfile, AGENT_PROFILES, WANDER_MAX_SPEED},
 };
 
-use crate::{level::Level, utils::line_intersect, GRAVITY_STRENGTH};
+/// Nodes closer together than this are the same node
+const DUPLICATE_NODE_DISTANCE: f32 = 1.0;
 
-use super::platformer_ai::{PLATFORMER_AI_AGENT_RADIUS, PLATFORMER_AI_JUMP_FORCE};
+/// Corners that a surface bends less than this at (in radians) are smooth
+const SMOOTH_CORNER_ANGLE: f32 = std::f32::consts::PI / 9.0;
+
+/// How many nodes each task finds the jumps of
+const JUMPABLE_CONNECTIONS_CHUNK_SIZE: usize = 16;
+
+/// Nodes with a normal at least this sideways are on walls, which is also when collisions
+/// count an agent as walled
+const WALL_NORMAL_X: f32 = 0.8;
 
 pub struct PathfindingPlugin;
 
 impl Plugin for PathfindingPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(Pathfinding {
-            nodes: Vec::new(),
-            goal_graph_node: None,
+            graphs: AGENT_PROFILES.iter().map(PathfindingGraph::new).collect(),
             goal_position: Vec2::ZERO,
             active: false,
-        });
+        })
+        .init_resource::<NodePlacementSettings>();
+    }
+}
+
+/// Builds the pathfinding graph of every agent profile for the level,
+/// or loads them from the cache if they were already built for the same level and profiles
+pub fn init_pathfinding_graph(
+    level: &Level,
+    pathfinding: &mut Pathfinding,
+    node_placement_settings: &NodePlacementSettings,
+) {
+    let loaded_graph_cache = load_graph_cache();
+
+    // Only the graphs of the current level are kept, otherwise every edit would add to the file
+    let mut graph_cache = GraphCache::new();
+    let mut built_graph = false;
+
+    for graph in pathfinding.graphs.iter_mut() {
+        let graph_cache_key = get_graph_cache_key(level, &graph.profile, node_placement_settings);
+
+        if let Some(nodes) = loaded_graph_cache.get(&graph_cache_key) {
+            info!("Loaded pathfinding graph from cache");
+
+            graph.nodes = nodes.clone();
+            graph.cache_key = graph_cache_key;
+        } else {
+            build_pathfinding_graph(level, graph, node_placement_settings);
+            built_graph = true;
+        }
+
+        graph_cache.insert(graph_cache_key, graph.nodes.clone());
+    }
+
+    // Also write it back if the file still has the graphs of an older level
+    if built_graph || loaded_graph_cache.len() != graph_cache.len() {
+        save_graph_cache(&graph_cache);
     }
 }
 
-pub fn init_pathfinding_graph(level: &Level, mut pathfinding: ResMut<Pathfinding>) {
-    place_nodes(&mut pathfinding, level);
+pub fn build_pathfinding_graph(
+    level: &Level,
+    pathfinding: &mut PathfindingGraph,
+    node_placement_settings: &NodePlacementSettings,
+) {
+    // Throw away the graph of the previous level
+    pathfinding.nodes.clear();
+    pathfinding.cache_key =
+        get_graph_cache_key(level, &pathfinding.profile, node_placement_settings);
+
+    place_nodes(pathfinding, level, node_placement_settings);
 
-    make_walkable_connections_2_way(&mut pathfinding);
+    make_walkable_connections_2_way(pathfinding);
 
-    remove_duplicate_nodes(&mut pathfinding);
+    remove_duplicate_nodes(pathfinding);
 
-    make_node_ids_indices(&mut pathfinding);
+    // Jumps are checked from the agent's position next to each node, which needs the normals
+    calculate_normals(pathfinding, level);
 
-    make_jumpable_connections(&mut pathfinding, level, PLATFORMER_AI_AGENT_RADIUS);
+    make_jumpable_connections(pathfinding, level);
 
-    calculate_normals(&mut pathfinding, level);
+    setup_corners(pathfinding);
 
-    setup_corners(&mut pathfinding);
+    make_droppable_connections(pathfinding, level);
 
-    // make_droppable_connections(&mut pathfinding, level);
+    make_shortcut_connections(pathfinding, level);
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PathfindingGraphConnectionType {
     Walkable,
     Jumpable,
     Droppable,
+    WallJumpable,
+    /// Through a shortcut pipe, which takes the agent from one entrance to another
+    Shortcut {
+        /// The points the pipe goes through, from the entrance to the exit
+        pipe: Vec<Vec2>,
+    },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PathfindingGraphConnection {
     pub node_id: usize,
     pub dist: f32,
@@ -54,7 +130,7 @@ pub struct PathfindingGraphConnection {
     pub effort: f32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PathfindingGraphNode {
     pub id: usize,
     pub position: Vec2,
@@ -63,102 +139,307 @@ pub struct PathfindingGraphNode {
     pub walkable_connections: Vec<PathfindingGraphConnection>,
     pub jumpable_connections: Vec<PathfindingGraphConnection>,
     pub droppable_connections: Vec<PathfindingGraphConnection>,
+    /// Jumps that push off of the wall the node is on. Wall nodes only have these, never regular jumps.
+    pub wall_jumpable_connections: Vec<PathfindingGraphConnection>,
+    /// To the nodes at the other entrances of the shortcut whose entrance this node is closest to
+    pub shortcut_connections: Vec<PathfindingGraphConnection>,
     pub normal: Vec2,
     pub is_corner: bool,
     pub is_external_corner: Option<bool>,
 }
 
+impl PathfindingGraphNode {
+    /// Every connection out of the node, of every type
+    pub fn connections(&self) -> impl Iterator<Item = &PathfindingGraphConnection> {
+        self.walkable_connections
+            .iter()
+            .chain(&self.jumpable_connections)
+            .chain(&self.droppable_connections)
+            .chain(&self.wall_jumpable_connections)
+            .chain(&self.shortcut_connections)
+    }
+}
+
+/// Controls how densely nodes are placed, which trades how well paths fit the level for how
+/// long the graph takes to build. Changing it rebuilds the graph.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct NodePlacementSettings {
+    /// Multiplies the node spacing of every agent profile
+    pub spacing_scale: f32,
+    /// Which surfaces get nodes
+    pub floors: bool,
+    pub walls: bool,
+    pub ceilings: bool,
+    /// Whether every corner gets a node. When this is off, corners that the surface
+    /// only bends a little at are spaced over like the rest of the surface.
+    pub always_place_corner_nodes: bool,
+}
+
+impl Default for NodePlacementSettings {
+    fn default() -> Self {
+        NodePlacementSettings {
+            spacing_scale: 1.0,
+            floors: true,
+            walls: true,
+            ceilings: false,
+            always_place_corner_nodes: true,
+        }
+    }
+}
+
 #[derive(Resource)]
 pub struct Pathfinding {
-    pub nodes: Vec<PathfindingGraphNode>,
-    pub goal_graph_node: Option<PathfindingGraphNode>,
+    /// One graph for each agent profile, in the same order as `AGENT_PROFILES`
+    pub graphs: Vec<PathfindingGraph>,
     pub goal_position: Vec2,
     pub active: bool,
 }
 
-pub fn place_nodes(pathfinding: &mut Pathfinding, level: &Level) {
-    let mut outer_container_seen = false;
+/// The nodes an agent with a certain profile can stand on, and how it can move between them
+pub struct PathfindingGraph {
+    pub profile: AgentProfile,
+    pub nodes: Vec<PathfindingGraphNode>,
+    /// The key of the level, profile and settings the graph was built from in the graph cache.
+    /// Anything worked out from the nodes, like an agent's path search, is out of date once it changes.
+    pub cache_key: u64,
+}
+
+impl PathfindingGraph {
+    pub fn new(profile: &AgentProfile) -> PathfindingGraph {
+        PathfindingGraph {
+            profile: *profile,
+            nodes: Vec::new(),
+            cache_key: 0,
+        }
+    }
+
+    /// The index of the node closest to a position, if there are any nodes
+    pub fn get_closest_node(&self, position: Vec2) -> Option<usize> {
+        let mut closest_node = None;
+        let mut closest_distance = f32::MAX;
+
+        for (node_index, node) in self.nodes.iter().enumerate() {
+            let distance = (position - node.position).length_squared();
+
+            if distance < closest_distance {
+                closest_distance = distance;
+                closest_node = Some(node_index);
+            }
+        }
+
+        closest_node
+    }
+}
+
+pub fn place_nodes(
+    pathfinding: &mut PathfindingGraph,
+    level: &Level,
+    node_placement_settings: &NodePlacementSettings,
+) {
+    let outer_container_index = get_outer_container_index(level);
+
+    let node_spacing = pathfinding.profile.node_spacing * node_placement_settings.spacing_scale;
+
+    // Crawling agents can get to every surface
+    let node_placement_settings = if pathfinding.profile.can_crawl {
+        NodePlacementSettings {
+            floors: true,
+            walls: true,
+            ceilings: true,
+            ..*node_placement_settings
+        }
+    } else {
+        *node_placement_settings
+    };
 
     // Place nodes
     for polygon_index in 0..level.polygons.len() {
         let polygon = &level.polygons[polygon_index];
-        if polygon.is_container {
-            outer_container_seen = !outer_container_seen;
+
+        // Skip the outside of the level
+        if outer_container_index == Some(polygon_index) {
+            continue;
         }
 
-        if outer_container_seen && polygon.is_container {
+        for surface in get_surfaces(&polygon.points, &node_placement_settings) {
+            place_nodes_on_surface(
+                pathfinding,
+                polygon_index,
+                &polygon.points,
+                &surface,
+                node_spacing,
+            );
+        }
+    }
+}
+
+/// Splits the lines of a polygon that should get nodes into surfaces.
+/// Each surface is a list of consecutive line indices, and is spaced with nodes as a whole.
+fn get_surfaces(
+    points: &[Vec2],
+    node_placement_settings: &NodePlacementSettings,
+) -> Vec<Vec<usize>> {
+    let line_count = points.len().saturating_sub(1);
+
+    let get_direction =
+        |line_index: usize| (points[line_index + 1] - points[line_index]).normalize();
+
+    let line_gets_nodes: Vec<bool> = (0..line_count)
+        .map(|line_index| {
+            // The normal of a line is its direction turned left, so this is the normal's y
+            let normal_y = get_direction(line_index).dot(Vec2::X);
+
+            if normal_y > 0.1 {
+                node_placement_settings.floors
+            } else if normal_y > -0.1 {
+                node_placement_settings.walls
+            } else {
+                node_placement_settings.ceilings
+            }
+        })
+        .collect();
+
+    // Whether a line carries on the surface of the line before it, without a node at the corner between them
+    let continues_surface = |line_index: usize| {
+        let previous_line_index = (line_index + line_count - 1) % line_count;
+
+        !node_placement_settings.always_place_corner_nodes
+            && line_gets_nodes[line_index]
+            && line_gets_nodes[previous_line_index]
+            && get_direction(previous_line_index).dot(get_direction(line_index))
+                >= SMOOTH_CORNER_ANGLE.cos()
+    };
+
+    // Start at a line that doesn't carry on from the one before it.
+    // If every line does, the surface goes all the way around and can start anywhere.
+    let first_line_index = (0..line_count)
+        .find(|line_index| !continues_surface(*line_index))
+        .unwrap_or(0);
+
+    let mut surfaces: Vec<Vec<usize>> = Vec::new();
+
+    for i in 0..line_count {
+        let line_index = (first_line_index + i) % line_count;
+
+        if !line_gets_nodes[line_index] {
             continue;
         }
 
-        for line_index in 1..polygon.points.len() {
-            let start = polygon.points[line_index - 1];
-            let end = polygon.points[line_index];
-
-            let mut start_to_end = end - start;
-
-            let length = start_to_end.length();
-
-            let nodes_on_line_count = (length.abs() / 20.0).ceil() as f32;
-            let dist_between_nodes_on_line = length / nodes_on_line_count;
-
-            start_to_end = start_to_end.normalize();
-
-            if start_to_end.dot(Vec2::X) > -0.1 {
-                for j in 0..(nodes_on_line_count as i32) {
-                    let node_pos = start + start_to_end * (j as f32 * dist_between_nodes_on_line);
-
-                    let mut new_node = PathfindingGraphNode {
-                        id: pathfinding.nodes.len(),
-                        position: node_pos,
-                        polygon_index: polygon_index,
-                        line_indicies: vec![(line_index - 1)],
-                        walkable_connections: Vec::new(),
-                        jumpable_connections: Vec::new(),
-                        droppable_connections: Vec::new(),
-                        normal: Vec2::ZERO,
-                        is_corner: false,
-                        is_external_corner: None,
-                    };
-
-                    if j > 0 {
-                        new_node
-                            .walkable_connections
-                            .push(PathfindingGraphConnection {
-                                node_id: pathfinding.nodes.len() - 1,
-                                dist: dist_between_nodes_on_line,
-                                connection_type: PathfindingGraphConnectionType::Walkable,
-                                effort: 0.0,
-                            });
-                    }
+        match surfaces.last_mut() {
+            Some(surface) if i > 0 && continues_surface(line_index) => surface.push(line_index),
+            _ => surfaces.push(vec![line_index]),
+        }
+    }
 
-                    pathfinding.nodes.push(new_node);
-                }
-                let new_node = PathfindingGraphNode {
-                    id: pathfinding.nodes.len(),
-                    position: end,
-                    polygon_index: polygon_index,
-                    line_indicies: vec![(line_index - 1)],
-                    walkable_connections: vec![PathfindingGraphConnection {
-                        node_id: pathfinding.nodes.len() - 1,
-                        dist: dist_between_nodes_on_line,
-                        connection_type: PathfindingGraphConnectionType::Walkable,
-                        effort: 0.0,
-                    }],
-                    jumpable_connections: Vec::new(),
-                    droppable_connections: Vec::new(),
-                    normal: Vec2::ZERO,
-                    is_corner: false,
-                    is_external_corner: None,
-                };
+    surfaces
+}
 
-                pathfinding.nodes.push(new_node);
-            }
+/// Places nodes evenly along a surface, including both of its ends, and connects them in a line
+fn place_nodes_on_surface(
+    pathfinding: &mut PathfindingGraph,
+    polygon_index: usize,
+    points: &[Vec2],
+    surface: &[usize],
+    node_spacing: f32,
+) {
+    let line_lengths: Vec<f32> = surface
+        .iter()
+        .map(|line_index| (points[line_index + 1] - points[*line_index]).length())
+        .collect();
+
+    let length: f32 = line_lengths.iter().sum();
+
+    let nodes_on_surface_count = (length.abs() / node_spacing).ceil().max(1.0) as usize;
+    let dist_between_nodes_on_surface = length / nodes_on_surface_count as f32;
+
+    // The line the next node is on, and how far along the surface that line starts
+    let mut surface_line = 0;
+    let mut surface_line_start = 0.0;
+
+    for j in 0..=nodes_on_surface_count {
+        let dist_along_surface = j as f32 * dist_between_nodes_on_surface;
+
+        while surface_line + 1 < surface.len()
+            && dist_along_surface >= surface_line_start + line_lengths[surface_line]
+        {
+            surface_line_start += line_lengths[surface_line];
+            surface_line += 1;
+        }
+
+        let line_index = surface[surface_line];
+        let start = points[line_index];
+        let end = points[line_index + 1];
+
+        // The last node is exactly on the end of the surface, so it lines up with the next surface
+        let node_pos = if j == nodes_on_surface_count {
+            end
+        } else {
+            start + (end - start).normalize() * (dist_along_surface - surface_line_start)
+        };
+
+        let mut new_node = PathfindingGraphNode {
+            id: pathfinding.nodes.len(),
+            position: node_pos,
+            polygon_index,
+            line_indicies: vec![line_index],
+            walkable_connections: Vec::new(),
+            jumpable_connections: Vec::new(),
+            droppable_connections: Vec::new(),
+            wall_jumpable_connections: Vec::new(),
+            shortcut_connections: Vec::new(),
+            normal: Vec2::ZERO,
+            is_corner: false,
+            is_external_corner: None,
+        };
+
+        if j > 0 {
+            new_node
+                .walkable_connections
+                .push(PathfindingGraphConnection {
+                    node_id: pathfinding.nodes.len() - 1,
+                    dist: dist_between_nodes_on_surface,
+                    connection_type: PathfindingGraphConnectionType::Walkable,
+                    effort: 0.0,
+                });
+        }
+
+        pathfinding.nodes.push(new_node);
+    }
+}
+
+/// Finds the container that wraps around the outside of the level, if there is one.
+/// When there are multiple containers, the biggest one is the outside of the level.
+fn get_outer_container_index(level: &Level) -> Option<usize> {
+    let mut outer_container_index = None;
+    let mut outer_container_area = 0.0;
+    let mut container_count = 0;
+
+    for polygon_index in 0..level.polygons.len() {
+        let polygon = &level.polygons[polygon_index];
+
+        if !polygon.is_container {
+            continue;
+        }
+
+        container_count += 1;
+
+        let area = polygon.area();
+
+        if area > outer_container_area {
+            outer_container_area = area;
+            outer_container_index = Some(polygon_index);
         }
     }
+
+    if container_count > 1 {
+        outer_container_index
+    } else {
+        None
+    }
 }
 
 /// Makes all of the connections between nodes 2-way
-pub fn make_walkable_connections_2_way(pathfinding: &mut Pathfinding) {
+pub fn make_walkable_connections_2_way(pathfinding: &mut PathfindingGraph) {
     for node_index in 0..pathfinding.nodes.len() {
         // Make a clone of the current node to appease the borrow checker
         let node = pathfinding.nodes[node_index].clone();
@@ -178,266 +459,362 @@ pub fn make_walkable_connections_2_way(pathfinding: &mut Pathfinding) {
     }
 }
 
-/// Removes redundant nodes that occupy the same position
-pub fn remove_duplicate_nodes(pathfinding: &mut Pathfinding) {
-    let mut i = 0;
-    while i < pathfinding.nodes.len() {
-        let mut j = i + 1;
-        while j < pathfinding.nodes.len() {
-            if (pathfinding.nodes[i].position - pathfinding.nodes[j].position).length_squared()
-                < 1.0
-            {
-                // Append the connections to the first node
-                let mut j_connections = pathfinding.nodes[j].walkable_connections.clone();
-                pathfinding.nodes[i]
-                    .walkable_connections
-                    .append(&mut j_connections);
+/// Merges nodes that occupy the same position into the first of them,
+/// and makes the ids and connections of the remaining nodes their indices
+pub fn remove_duplicate_nodes(pathfinding: &mut PathfindingGraph) {
+    let nodes = std::mem::take(&mut pathfinding.nodes);
 
-                // Record the id of the nodes
-                let first_node_id = pathfinding.nodes[i].id;
-                let second_node_id = pathfinding.nodes[j].id;
+    // The remaining nodes in each cell, cells are as big as the merge distance
+    // so a node's duplicates can only be in its own cell or the ones next to it
+    let mut spatial_hash: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
 
-                // Append the line indicies to the first node
-                let second_node_line_index = pathfinding.nodes[j].line_indicies[0];
-                pathfinding.nodes[i]
-                    .line_indicies
-                    .push(second_node_line_index);
+    // Which remaining node each of the original nodes ended up as
+    let mut new_indices: Vec<usize> = Vec::with_capacity(nodes.len());
 
-                // Remove the second node
-                pathfinding.nodes.remove(j);
+    for node in nodes {
+        let cell = get_duplicate_node_cell(node.position);
 
-                // Update the connections of the nodes that were connected to the second node
-                for node in &mut pathfinding.nodes {
-                    for connection in &mut node.walkable_connections {
-                        if connection.node_id == second_node_id {
-                            connection.node_id = first_node_id;
-                        }
+        let mut duplicate_of: Option<usize> = None;
+
+        for offset_y in -1..=1 {
+            for offset_x in -1..=1 {
+                let Some(cell_nodes) = spatial_hash.get(&(cell.0 + offset_x, cell.1 + offset_y))
+                else {
+                    continue;
+                };
+
+                for node_index in cell_nodes.iter().copied() {
+                    let is_duplicate = (pathfinding.nodes[node_index].position - node.position)
+                        .length_squared()
+                        < DUPLICATE_NODE_DISTANCE * DUPLICATE_NODE_DISTANCE;
+
+                    // Merge into the first node, like the nodes were compared in order
+                    if is_duplicate && duplicate_of.is_none_or(|first| node_index < first) {
+                        duplicate_of = Some(node_index);
                     }
                 }
-            } else {
-                j += 1;
             }
         }
-        i += 1;
+
+        match duplicate_of {
+            Some(first_node_index) => {
+                let first_node = &mut pathfinding.nodes[first_node_index];
+
+                // Append the connections and the line to the first node
+                first_node
+                    .walkable_connections
+                    .extend(node.walkable_connections);
+                first_node.line_indicies.push(node.line_indicies[0]);
+
+                new_indices.push(first_node_index);
+            }
+            None => {
+                let node_index = pathfinding.nodes.len();
+
+                spatial_hash.entry(cell).or_default().push(node_index);
+                new_indices.push(node_index);
+
+                pathfinding.nodes.push(node);
+            }
+        }
+    }
+
+    // The ids are still the indices from before the nodes were merged
+    for (node_index, node) in pathfinding.nodes.iter_mut().enumerate() {
+        node.id = node_index;
+
+        for connection in node.walkable_connections.iter_mut() {
+            connection.node_id = new_indices[connection.node_id];
+        }
     }
 }
 
-/// Updates the ids and connections to reflect the indices of the nodes
-pub fn make_node_ids_indices(pathfinding: &mut Pathfinding) {
-    let pathfinding_nodes_copy = pathfinding.nodes.clone();
+fn get_duplicate_node_cell(position: Vec2) -> (i32, i32) {
+    let cell = (position / DUPLICATE_NODE_DISTANCE).floor();
 
-    for node_index in 0..pathfinding.nodes.len() {
-        pathfinding.nodes[node_index].id = node_index;
+    (cell.x as i32, cell.y as i32)
+}
 
-        for connection_index in 0..pathfinding.nodes[node_index].walkable_connections.len() {
-            let connected_node = pathfinding_nodes_copy
+pub fn make_jumpable_connections(pathfinding: &mut PathfindingGraph, level: &Level) {
+    let profile = pathfinding.profile;
+    let radius = profile.radius;
+
+    let nodes = &pathfinding.nodes;
+
+    // Where the agent's center is when it's standing on each node
+    let jump_positions: Vec<Vec2> = nodes
+        .iter()
+        .map(|node| node.position + node.normal * radius)
+        .collect();
+
+    // The nodes sorted from left to right, so the nodes in horizontal range can be found quickly
+    let mut nodes_by_x: Vec<usize> = (0..nodes.len()).collect();
+    nodes_by_x.sort_by(|a, b| jump_positions[*a].x.total_cmp(&jump_positions[*b].x));
+
+    let sorted_x: Vec<f32> = nodes_by_x
+        .iter()
+        .map(|node_index| jump_positions[*node_index].x)
+        .collect();
+
+    // A jump can go further sideways the further down it lands,
+    // so the furthest a jump can go is bounded by the height of the graph
+    let min_y = jump_positions
+        .iter()
+        .map(|position| position.y)
+        .fold(f32::MAX, f32::min);
+    let max_y = jump_positions
+        .iter()
+        .map(|position| position.y)
+        .fold(f32::MIN, f32::max);
+    let max_jump_distance_x = get_max_jump_distance_x(max_y - min_y, &profile);
+
+    let task_pool = ComputeTaskPool::get_or_init(TaskPool::default);
+
+    let node_indices: Vec<usize> = (0..nodes.len()).collect();
+
+    // Each node's jumps only depend on the level and the other nodes, so they can be found in parallel
+    let jumpable_connections: Vec<Vec<Vec<PathfindingGraphConnection>>> = node_indices
+        .par_chunk_map(task_pool, JUMPABLE_CONNECTIONS_CHUNK_SIZE, |node_indices| {
+            node_indices
                 .iter()
-                .find(|n| {
-                    n.id == pathfinding.nodes[node_index].walkable_connections[connection_index]
-                        .node_id
-                })
-                .unwrap();
+                .map(|i| {
+                    let main_position = jump_positions[*i];
 
-            let connected_node_id = pathfinding_nodes_copy
-                .iter()
-                .position(|n| n.id == connected_node.id)
-                .unwrap();
+                    let first =
+                        sorted_x.partition_point(|x| *x < main_position.x - max_jump_distance_x);
+                    let last =
+                        sorted_x.partition_point(|x| *x <= main_position.x + max_jump_distance_x);
+
+                    let mut candidates: Vec<usize> = nodes_by_x[first..last]
+                        .iter()
+                        .copied()
+                        .filter(|j| is_in_jump_range(jump_positions[*j] - main_position, &profile))
+                        .collect();
+
+                    // Keep the connections in the same order as the nodes
+                    candidates.sort();
 
-            pathfinding.nodes[node_index].walkable_connections[connection_index].node_id =
-                connected_node_id;
+                    get_jumpable_connections(nodes, *i, &candidates, level, &profile)
+                })
+                .collect()
+        });
+
+    for (node, jumpable_connections) in pathfinding
+        .nodes
+        .iter_mut()
+        .zip(jumpable_connections.into_iter().flatten())
+    {
+        // Agents on a wall are never grounded, so the only jump they can make is a wall jump.
+        // Any jump off of a wall that doesn't hit it has to go away from it, which is exactly
+        // what a wall jump checks for, so no jumps are lost by keeping them all as wall jumps.
+        if is_wall_node(node) {
+            node.wall_jumpable_connections = jumpable_connections;
+        } else {
+            node.jumpable_connections = jumpable_connections;
         }
     }
 }
 
-pub fn make_jumpable_connections(pathfinding: &mut Pathfinding, level: &Level, radius: f32) {
-    for i in 0..pathfinding.nodes.len() {
-        let main_node = &pathfinding.nodes[i];
+fn is_wall_node(node: &PathfindingGraphNode) -> bool {
+    node.normal.x.abs() >= WALL_NORMAL_X
+}
 
-        let mut jumpable_connections: Vec<PathfindingGraphConnection> = Vec::new();
+/// Finds which of the candidate nodes can be jumped to from node `i`.
+/// Jumps from wall nodes are wall jumps.
+fn get_jumpable_connections(
+    nodes: &[PathfindingGraphNode],
+    i: usize,
+    candidates: &[usize],
+    level: &Level,
+    profile: &AgentProfile,
+) -> Vec<PathfindingGraphConnection> {
+    let main_node = &nodes[i];
 
-        'other_nodes: for j in 0..pathfinding.nodes.len() {
-            // Make sure we're not comparing the same node
-            if i == j {
-                continue;
-            }
+    let mut jumpable_connections: Vec<PathfindingGraphConnection> = Vec::new();
+
+    // Agents can't jump off of ceilings
+    if main_node.normal.y < -0.1 {
+        return jumpable_connections;
+    }
+
+    let wall_jump = is_wall_node(main_node);
+
+    let connection_type = if wall_jump {
+        PathfindingGraphConnectionType::WallJumpable
+    } else {
+        PathfindingGraphConnectionType::Jumpable
+    };
+
+    'other_nodes: for j in candidates.iter().copied() {
+        // Make sure we're not comparing the same node
+        if i == j {
+            continue;
+        }
 
-            let other_node = &pathfinding.nodes[j];
+        let other_node = &nodes[j];
 
-            // Make sure the nodes are not on the same polygon
-            if main_node.polygon_index == other_node.polygon_index {
+        if wall_jump {
+            // A wall jump pushes the agent away from the wall, so it has to go that way
+            let delta_x = (other_node.position + other_node.normal * profile.radius).x
+                - (main_node.position + main_node.normal * profile.radius).x;
+
+            if delta_x * main_node.normal.x <= 0.0 {
                 continue;
             }
 
-            for polygon_index in 0..level.polygons.len() {
-                let polygon = &level.polygons[polygon_index];
+            // It can land anywhere but back on the wall, even on the same polygon.
+            // That's what lets agents climb up between the two walls of a shaft.
+            let same_line = main_node.polygon_index == other_node.polygon_index
+                && other_node
+                    .line_indicies
+                    .iter()
+                    .any(|line_index| main_node.line_indicies.contains(line_index));
 
-                'polygon_lines: for line_index in 1..polygon.points.len() {
-                    if main_node.polygon_index == polygon_index
-                        && main_node.line_indicies.contains(&(line_index - 1))
-                        || other_node.polygon_index == polygon_index
-                            && other_node.line_indicies.contains(&(line_index - 1))
-                    {
-                        continue 'polygon_lines;
-                    }
+            if same_line {
+                continue;
+            }
+        }
+        // Make sure the nodes are not on the same polygon
+        else if main_node.polygon_index == other_node.polygon_index {
+            continue;
+        }
 
-                    let start = polygon.points[line_index - 1];
-                    let end = polygon.points[line_index];
+        // Only the lines near the straight line between the nodes can block it
+        let nearby_lines = level
+            .segment_index
+            .query_segment(main_node.position, other_node.position);
 
-                    let intersection =
-                        line_intersect(start, end, main_node.position, other_node.position);
+        for polygon_line in nearby_lines {
+            let polygon_index = polygon_line.polygon_index;
+            let line_index = polygon_line.line_index;
 
-                    if intersection.is_some() {
-                        continue 'other_nodes;
-                    }
-                }
+            if main_node.polygon_index == polygon_index
+                && main_node.line_indicies.contains(&line_index)
+                || other_node.polygon_index == polygon_index
+                    && other_node.line_indicies.contains(&line_index)
+            {
+                continue;
             }
 
-            let jumpable_velocity = jumpability_check(main_node, other_node, level, radius);
+            let (start, end) = level.get_polygon_line(&polygon_line);
+
+            let intersection = line_intersect(start, end, main_node.position, other_node.position);
 
-            if jumpable_velocity.is_none() {
+            if intersection.is_some() {
                 continue 'other_nodes;
             }
+        }
 
-            jumpable_connections.push(PathfindingGraphConnection {
-                node_id: j,
-                dist: (main_node.position - other_node.position).length(),
-                connection_type: PathfindingGraphConnectionType::Jumpable,
-                effort: jumpable_velocity.unwrap(),
-            });
+        let jumpable_velocity = jumpability_check(main_node, other_node, level, profile);
+
+        if jumpable_velocity.is_none() {
+            continue 'other_nodes;
         }
 
-        pathfinding.nodes[i].jumpable_connections = jumpable_connections;
+        jumpable_connections.push(PathfindingGraphConnection {
+            node_id: j,
+            dist: (main_node.position - other_node.position).length(),
+            connection_type: connection_type.clone(),
+            effort: jumpable_velocity.unwrap(),
+        });
     }
+
+    jumpable_connections
+}
+
+/// Whether a jump with the profile's jump force can reach `delta_p` at all, ignoring the level
+fn is_in_jump_range(delta_p: Vec2, profile: &AgentProfile) -> bool {
+    let acceleration = Vec2::new(0.0, -profile.gravity);
+    let v_max = profile.jump_force;
+    let b1 = delta_p.dot(acceleration) + v_max * v_max;
+    let discriminant = b1 * b1 - acceleration.dot(acceleration) * delta_p.dot(delta_p);
+
+    discriminant >= 0.0
+}
+
+/// How far sideways a jump can go when it lands `drop` below where it started.
+/// This is where the edge of the range in `is_in_jump_range` is, plus a bit of leeway.
+fn get_max_jump_distance_x(drop: f32, profile: &AgentProfile) -> f32 {
+    let v_max_sq = profile.jump_force * profile.jump_force;
+
+    let max_distance_sq = v_max_sq * v_max_sq / (profile.gravity * profile.gravity)
+        + 2.0 * v_max_sq * drop.max(0.0) / profile.gravity;
+
+    max_distance_sq.sqrt() + 1.0
 }
 
 pub fn jumpability_check(
     start_graph_node: &PathfindingGraphNode,
     goal_graph_node: &PathfindingGraphNode,
     level: &Level,
-    radius: f32,
+    profile: &AgentProfile,
 ) -> Option<f32> {
     let start_node = start_graph_node;
-    let start_pos = start_node.position;
-
     let goal_node = goal_graph_node;
-    let goal_pos = goal_node.position;
+    let radius = profile.radius;
+
+    // The agent's center is pushed out from the surface by its radius,
+    // so that's where it jumps from and lands
+    let start_pos = start_node.position + start_node.normal * radius;
+    let goal_pos = goal_node.position + goal_node.normal * radius;
 
     let delta_p = goal_pos - start_pos;
-    let acceleration = Vec2::new(0.0, -GRAVITY_STRENGTH);
-    let v_max = PLATFORMER_AI_JUMP_FORCE;
-    let b1 = delta_p.dot(acceleration) + v_max * v_max;
-    let discriminant = b1 * b1 - acceleration.dot(acceleration) * delta_p.dot(delta_p);
+    let acceleration = Vec2::new(0.0, -profile.gravity);
 
-    let mut jump_possible = discriminant >= 0.0;
+    if !is_in_jump_range(delta_p, profile) {
+        return None;
+    }
 
     let t_low_energy = (4.0 * delta_p.dot(delta_p) / acceleration.dot(acceleration))
         .sqrt()
         .sqrt();
     let launch_velocity = delta_p / t_low_energy - acceleration * t_low_energy / 2.0;
-    let timestep = t_low_energy / 10 as f32;
-
-    if jump_possible {
-        'polygon: for polygon_index in 0..level.polygons.len() {
-            let polygon = &level.polygons[polygon_index];
-            'line: for line_index in 1..polygon.points.len() {
-                let start_node_on_line = start_node.polygon_index == polygon_index
-                    && start_node.line_indicies.contains(&(line_index - 1));
-                let goal_node_on_line = goal_node.polygon_index == polygon_index
-                    && goal_node.line_indicies.contains(&(line_index - 1));
-
-                if start_node_on_line || goal_node_on_line {
-                    continue 'line;
-                }
-
-                let line_start = polygon.points[line_index - 1];
-                let line_end = polygon.points[line_index];
-
-                let mut prev_pos = start_pos;
-
-                for i in 1..10 {
-                    let t = timestep * i as f32;
-                    let pos = start_pos + launch_velocity * t + acceleration * t * t / 2.0;
-
-                    let line_dir = (pos - prev_pos).normalize();
-
-                    let line_normal = Vec2::new(-line_dir.y, line_dir.x);
-
-                    let line_beginning_offset_1 = prev_pos + line_normal * radius;
-                    let line_beginning_offset_2 = prev_pos - line_normal * radius;
-                    let line_end_offset_1 = pos + line_normal * radius;
-                    let line_end_offset_2 = pos - line_normal * radius;
-
-                    let offset_1_intersection = line_intersect(
-                        line_beginning_offset_1,
-                        line_end_offset_1,
-                        line_start,
-                        line_end,
-                    );
-
-                    if offset_1_intersection.is_some() {
-                        jump_possible = false;
-                        break 'polygon;
-                    }
-
-                    let offset_2_intersection = line_intersect(
-                        line_beginning_offset_2,
-                        line_end_offset_2,
-                        line_start,
-                        line_end,
-                    );
-
-                    if offset_2_intersection.is_some() {
-                        jump_possible = false;
-                        break 'polygon;
-                    }
-
-                    prev_pos = pos;
-                }
-
-                let line_dir = (goal_pos - prev_pos).normalize();
-
-                let line_normal = Vec2::new(-line_dir.y, line_dir.x);
-
-                let line_beginning_offset_1 = prev_pos + line_normal * radius;
-                let line_beginning_offset_2 = prev_pos - line_normal * radius;
-                let line_end_offset_1 = goal_pos + line_normal * radius;
-                let line_end_offset_2 = goal_pos - line_normal * radius;
-
-                let offset_1_intersection = line_intersect(
-                    line_beginning_offset_1,
-                    line_end_offset_1,
-                    line_start,
-                    line_end,
-                );
-
-                if offset_1_intersection.is_some() {
-                    jump_possible = false;
-                    break 'polygon;
-                }
-
-                let offset_2_intersection = line_intersect(
-                    line_beginning_offset_2,
-                    line_end_offset_2,
-                    line_start,
-                    line_end,
-                );
-
-                if offset_2_intersection.is_some() {
-                    jump_possible = false;
-                    break 'polygon;
-                }
-            }
+    let timestep = t_low_energy / 10_f32;
+
+    // The agent is touching the lines it jumps from and lands on
+    let ignored_lines: Vec<PolygonLine> = start_node
+        .line_indicies
+        .iter()
+        .map(|line_index| PolygonLine {
+            polygon_index: start_node.polygon_index,
+            line_index: *line_index,
+        })
+        .chain(
+            goal_node
+                .line_indicies
+                .iter()
+                .map(|line_index| PolygonLine {
+                    polygon_index: goal_node.polygon_index,
+                    line_index: *line_index,
+                }),
+        )
+        .collect();
+
+    // Sweep the agent along the jump arc
+    let mut prev_pos = start_pos;
+
+    for i in 1..=10 {
+        let pos = if i == 10 {
+            goal_pos
+        } else {
+            let t = timestep * i as f32;
+            start_pos + launch_velocity * t + acceleration * t * t / 2.0
+        };
+
+        if level
+            .circle_cast_ignoring(prev_pos, pos, radius, &ignored_lines)
+            .is_some()
+        {
+            return None;
         }
+
+        prev_pos = pos;
     }
 
-    return if jump_possible {
-        Some(launch_velocity.length())
-    } else {
-        None
-    };
+    Some(launch_velocity.length())
 }
 
-pub fn calculate_normals(pathfinding: &mut Pathfinding, level: &Level) {
+pub fn calculate_normals(pathfinding: &mut PathfindingGraph, level: &Level) {
     for node_index in 0..pathfinding.nodes.len() {
         let node = &pathfinding.nodes[node_index];
 
@@ -456,7 +833,7 @@ pub fn calculate_normals(pathfinding: &mut Pathfinding, level: &Level) {
     }
 }
 
-pub fn setup_corners(pathfinding: &mut Pathfinding) {
+pub fn setup_corners(pathfinding: &mut PathfindingGraph) {
     for node_index in 0..pathfinding.nodes.len() {
         // let node = &mut pathfinding.nodes[node_index];
 
@@ -478,36 +855,632 @@ pub fn setup_corners(pathfinding: &mut Pathfinding) {
     }
 }
 
-// pub fn make_droppable_connections(pathfinding: &mut Pathfinding, level: &Level) {
-//     // For each node
+/// Connects ledge corners to the surface an agent lands on after walking off them
+pub fn make_droppable_connections(pathfinding: &mut PathfindingGraph, level: &Level) {
+    let profile = pathfinding.profile;
+    let radius = profile.radius;
+
+    for node_index in 0..pathfinding.nodes.len() {
+        let node = &pathfinding.nodes[node_index];
+
+        // Only upward facing external corners can be walked off of
+        if node.is_external_corner != Some(true) || node.normal.y <= 0.1 {
+            continue;
+        }
+
+        // The side of the corner that drops away
+        let outward = node.normal.x.signum();
+
+        if node.normal.x.abs() < 0.1 {
+            continue;
+        }
+
+        // The agent starts standing on top of the corner, and it's touching the lines of the ledge
+        let drop_start = node.position + Vec2::new(0.0, radius);
+        let ignored_lines: Vec<PolygonLine> = node
+            .line_indicies
+            .iter()
+            .map(|line_index| PolygonLine {
+                polygon_index: node.polygon_index,
+                line_index: *line_index,
+            })
+            .collect();
+
+        let hit = drop_sweep(level, drop_start, outward, &ignored_lines, &profile);
+
+        if hit.is_none() {
+            continue;
+        }
+
+        let hit = hit.unwrap();
+        let polygon_index = hit.polygon_line.polygon_index;
+        let line_index = hit.polygon_line.line_index;
+        let hit_point = hit.point;
+
+        // Land on the closest node of the line that was hit
+        let mut landing_node_id: Option<usize> = None;
+        let mut closest_distance = f32::MAX;
+
+        for other_node in pathfinding.nodes.iter() {
+            if other_node.id == node.id
+                || other_node.polygon_index != polygon_index
+                || !other_node.line_indicies.contains(&line_index)
+                || other_node.normal.y <= 0.1
+            {
+                continue;
+            }
+
+            let distance = (other_node.position - hit_point).length_squared();
+
+            if distance < closest_distance {
+                closest_distance = distance;
+                landing_node_id = Some(other_node.id);
+            }
+        }
+
+        if landing_node_id.is_none() {
+            continue;
+        }
+
+        let landing_node_id = landing_node_id.unwrap();
+
+        let already_connected = node
+            .walkable_connections
+            .iter()
+            .any(|connection| connection.node_id == landing_node_id);
+
+        if already_connected {
+            continue;
+        }
+
+        let dist = (pathfinding.nodes[landing_node_id].position - node.position).length();
+
+        pathfinding.nodes[node_index]
+            .droppable_connections
+            .push(PathfindingGraphConnection {
+                node_id: landing_node_id,
+                dist,
+                connection_type: PathfindingGraphConnectionType::Droppable,
+                effort: 0.0,
+            });
+    }
+}
+
+/// Connects the node closest to each entrance of a shortcut to the nodes closest to its other
+/// entrances. Going through a shortcut costs the length of the pipe.
+pub fn make_shortcut_connections(pathfinding: &mut PathfindingGraph, level: &Level) {
+    for shortcut in &level.shortcuts {
+        let entrance_nodes: Vec<Option<usize>> = shortcut
+            .entrances
+            .iter()
+            .map(|entrance| pathfinding.get_closest_node(*entrance))
+            .collect();
+
+        for (from, from_node) in entrance_nodes.iter().enumerate() {
+            for (to, to_node) in entrance_nodes.iter().enumerate() {
+                let (Some(from_node), Some(to_node)) = (*from_node, *to_node) else {
+                    continue;
+                };
+
+                // Entrances right next to each other don't go anywhere
+                if from == to || from_node == to_node {
+                    continue;
+                }
+
+                let pipe = shortcut.get_pipe(from, to);
+                let pipe_length: f32 = pipe
+                    .windows(2)
+                    .map(|points| (points[1] - points[0]).length())
+                    .sum();
+
+                // The nodes aren't right on the entrances, so getting in and out of the pipe
+                // is part of the cost. Otherwise the connection could cost less than the
+                // distance between its nodes, which A* relies on it never doing.
+                let entrance_dist =
+                    (shortcut.entrances[from] - pathfinding.nodes[from_node].position).length();
+                let exit_dist =
+                    (pathfinding.nodes[to_node].position - shortcut.entrances[to]).length();
+
+                pathfinding.nodes[from_node].shortcut_connections.push(
+                    PathfindingGraphConnection {
+                        node_id: to_node,
+                        dist: entrance_dist + pipe_length + exit_dist,
+                        connection_type: PathfindingGraphConnectionType::Shortcut { pipe },
+                        effort: 0.0,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Sweeps the agent along the arc it falls in after walking off a ledge at `start`
+/// towards `outward`, and returns the first line it lands on
+fn drop_sweep(
+    level: &Level,
+    start: Vec2,
+    outward: f32,
+    ignored_lines: &[PolygonLine],
+    profile: &AgentProfile,
+) -> Option<CircleCastHit> {
+    let level_bottom = -level.half_size.y * level.grid_size;
+
+    // The agent keeps the speed it was walking at while it falls,
+    // so it can land quite a way out from the ledge
+    let velocity = Vec2::new(outward * WANDER_MAX_SPEED, 0.0);
+    let acceleration = Vec2::new(0.0, -profile.gravity);
+
+    // Sweep the agent along the arc one frame at a time, until it falls out of the level
+    let mut prev_pos = start;
+    let mut t = 0.0;
+
+    while prev_pos.y > level_bottom {
+        t += 1.0;
+
+        let pos = start + velocity * t + acceleration * t * t / 2.0;
+
+        let hit = level.circle_cast_ignoring(prev_pos, pos, profile.radius, ignored_lines);
+
+        if hit.is_some() {
+            return hit;
+        }
+
+        prev_pos = pos;
+    }
+
+    None
+}
 
-//     for i in 0..pathfinding.nodes.len() {
-//         let node = &pathfinding.nodes[i];
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{
+        ai::{platformer_ai::DEFAULT_AGENT_PROFILE, test_utils::for_each_shipped_graph},
+        level::{
+            loader::{LevelData, PolygonData},
+            tileset::Tileset,
+        },
+    };
 
-//         if node.normal.y > 0.1 {
-//             continue;
-//         }
+    /// The original jump search, which tries every pair of nodes against every line on one thread.
+    /// It's what the pruned, parallel search is checked and timed against.
+    fn make_jumpable_connections_reference(pathfinding: &mut PathfindingGraph, level: &Level) {
+        let profile = pathfinding.profile;
 
-//         let mut droppable_connections: Vec<usize> = Vec::new();
+        for i in 0..pathfinding.nodes.len() {
+            let main_node = &pathfinding.nodes[i];
 
-//         // Boxcast down
+            let mut jumpable_connections: Vec<PathfindingGraphConnection> = Vec::new();
 
-//         // // For each line in the level
-//         // for polygon_index in 0..level.polygons.len() {
-//         //     let polygon = &level.polygons[polygon_index];
+            // Jumps off of walls are wall jumps, which the reference doesn't know about
+            if is_wall_node(main_node) {
+                continue;
+            }
 
-//         //     for line_index in 1..polygon.points.len() {
-//         //         let start = polygon.points[line_index - 1];
-//         //         let end = polygon.points[line_index];
+            'other_nodes: for j in 0..pathfinding.nodes.len() {
+                let other_node = &pathfinding.nodes[j];
 
-//         //         // If the boxcast hits a node below, add it to the droppable connections
-//         //     }
-//         // }
+                if i == j || main_node.polygon_index == other_node.polygon_index {
+                    continue;
+                }
 
-//         // If the boxcast hits a node below, add it to the droppable connections
-//     }
+                for (polygon_index, polygon) in level.polygons.iter().enumerate() {
+                    for line_index in 1..polygon.points.len() {
+                        if main_node.polygon_index == polygon_index
+                            && main_node.line_indicies.contains(&(line_index - 1))
+                            || other_node.polygon_index == polygon_index
+                                && other_node.line_indicies.contains(&(line_index - 1))
+                        {
+                            continue;
+                        }
 
-//     // Boxcast down
+                        let start = polygon.points[line_index - 1];
+                        let end = polygon.points[line_index];
 
-//     // If the boxcast hits a node below, add it to the droppable connections
-// }
+                        if line_intersect(start, end, main_node.position, other_node.position)
+                            .is_some()
+                        {
+                            continue 'other_nodes;
+                        }
+                    }
+                }
+
+                if let Some(effort) = jumpability_check(main_node, other_node, level, &profile) {
+                    jumpable_connections.push(PathfindingGraphConnection {
+                        node_id: j,
+                        dist: (main_node.position - other_node.position).length(),
+                        connection_type: PathfindingGraphConnectionType::Jumpable,
+                        effort,
+                    });
+                }
+            }
+
+            pathfinding.nodes[i].jumpable_connections = jumpable_connections;
+        }
+    }
+
+    fn get_test_tileset() -> Tileset {
+        Tileset::from_bytes(include_bytes!("../../assets/tiles.tileset.json")).unwrap()
+    }
+
+    /// A big room full of staggered platforms
+    fn get_large_test_level() -> Level {
+        let width = 80;
+        let height = 50;
+
+        let mut level_grid_data = vec![vec![0; width]; height];
+
+        for (y, row) in level_grid_data.iter_mut().enumerate() {
+            for (x, tile) in row.iter_mut().enumerate() {
+                let is_wall = x == 0 || y == 0 || x == width - 1 || y == height - 1;
+                let is_platform = y % 5 == 0 && (x + y * 3) % 11 < 4;
+
+                if is_wall || is_platform {
+                    *tile = 1;
+                }
+            }
+        }
+
+        Level::from_grid(&level_grid_data, &get_test_tileset(), 32.0).unwrap()
+    }
+
+    /// Builds the graph up to the point where the jumps are added
+    fn get_test_pathfinding(level: &Level) -> PathfindingGraph {
+        let mut pathfinding = PathfindingGraph::new(&DEFAULT_AGENT_PROFILE);
+
+        place_nodes(&mut pathfinding, level, &NodePlacementSettings::default());
+        make_walkable_connections_2_way(&mut pathfinding);
+        remove_duplicate_nodes(&mut pathfinding);
+        calculate_normals(&mut pathfinding, level);
+
+        pathfinding
+    }
+
+    fn get_jumps(pathfinding: &PathfindingGraph) -> Vec<(usize, usize, f32)> {
+        pathfinding
+            .nodes
+            .iter()
+            .flat_map(|node| {
+                node.jumpable_connections
+                    .iter()
+                    .map(|connection| (node.id, connection.node_id, connection.effort))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn jumpable_connections_match_reference() {
+        let level_data = LevelData::from_bytes(include_bytes!("../../assets/level.json")).unwrap();
+        let level = Level::from_level_data(&level_data, &get_test_tileset(), 32.0).unwrap();
+
+        let mut reference_pathfinding = get_test_pathfinding(&level);
+        let mut pathfinding = get_test_pathfinding(&level);
+
+        make_jumpable_connections_reference(&mut reference_pathfinding, &level);
+        make_jumpable_connections(&mut pathfinding, &level);
+
+        assert_eq!(get_jumps(&pathfinding), get_jumps(&reference_pathfinding));
+    }
+
+    #[test]
+    fn smooth_corners_can_be_spaced_over() {
+        // A room with a floor that bends a little in the middle
+        let level = Level::from_polygons(
+            &[PolygonData {
+                points: vec![
+                    Vec2::new(-100.0, -50.0),
+                    Vec2::new(0.0, -45.0),
+                    Vec2::new(100.0, -50.0),
+                    Vec2::new(100.0, 50.0),
+                    Vec2::new(-100.0, 50.0),
+                ],
+                is_container: true,
+            }],
+            32.0,
+        )
+        .unwrap();
+
+        let bend = Vec2::new(0.0, -45.0);
+
+        let mut node_placement_settings = NodePlacementSettings::default();
+
+        let mut pathfinding = PathfindingGraph::new(&DEFAULT_AGENT_PROFILE);
+        build_pathfinding_graph(&level, &mut pathfinding, &node_placement_settings);
+
+        assert!(pathfinding.nodes.iter().any(|node| node.position == bend));
+
+        node_placement_settings.always_place_corner_nodes = false;
+
+        let mut pathfinding = PathfindingGraph::new(&DEFAULT_AGENT_PROFILE);
+        build_pathfinding_graph(&level, &mut pathfinding, &node_placement_settings);
+
+        assert!(pathfinding.nodes.iter().all(|node| node.position != bend));
+        // The corners of the room are still there
+        assert!(pathfinding
+            .nodes
+            .iter()
+            .any(|node| node.position == Vec2::new(100.0, -50.0)));
+    }
+
+    #[test]
+    fn crawlers_walk_around_corners_onto_ceilings() {
+        let level = Level::from_polygons(
+            &[PolygonData {
+                points: vec![
+                    Vec2::new(-100.0, -50.0),
+                    Vec2::new(100.0, -50.0),
+                    Vec2::new(100.0, 50.0),
+                    Vec2::new(-100.0, 50.0),
+                ],
+                is_container: true,
+            }],
+            32.0,
+        )
+        .unwrap();
+
+        let crawl_profile = AgentProfile {
+            can_crawl: true,
+            ..DEFAULT_AGENT_PROFILE
+        };
+
+        let mut pathfinding = PathfindingGraph::new(&crawl_profile);
+        build_pathfinding_graph(&level, &mut pathfinding, &NodePlacementSettings::default());
+
+        let is_ceiling_node = |node: &PathfindingGraphNode| node.normal.y < -0.9;
+
+        assert!(pathfinding.nodes.iter().any(is_ceiling_node));
+        assert!(pathfinding
+            .nodes
+            .iter()
+            .any(|node| !node.is_corner && is_wall_node(node)));
+
+        // Walk from the middle of the floor using only walkable connections
+        let start = pathfinding
+            .nodes
+            .iter()
+            .position(|node| node.position == Vec2::new(0.0, -50.0))
+            .unwrap();
+
+        let mut reached = vec![false; pathfinding.nodes.len()];
+        let mut open = vec![start];
+        reached[start] = true;
+
+        while let Some(node_index) = open.pop() {
+            for connection in &pathfinding.nodes[node_index].walkable_connections {
+                if !reached[connection.node_id] {
+                    reached[connection.node_id] = true;
+                    open.push(connection.node_id);
+                }
+            }
+        }
+
+        let ceiling_middle = pathfinding
+            .nodes
+            .iter()
+            .position(|node| node.position == Vec2::new(0.0, 50.0))
+            .unwrap();
+
+        assert!(reached[ceiling_middle]);
+    }
+
+    #[test]
+    fn ledges_drop_to_the_floor_below() {
+        // A room with a ledge on the left that's too high to jump back up
+        let level = Level::from_polygons(
+            &[PolygonData {
+                points: vec![
+                    Vec2::new(-200.0, 0.0),
+                    Vec2::new(0.0, 0.0),
+                    Vec2::new(0.0, -150.0),
+                    Vec2::new(200.0, -150.0),
+                    Vec2::new(200.0, 100.0),
+                    Vec2::new(-200.0, 100.0),
+                ],
+                is_container: true,
+            }],
+            32.0,
+        )
+        .unwrap();
+
+        let mut pathfinding = PathfindingGraph::new(&DEFAULT_AGENT_PROFILE);
+        build_pathfinding_graph(&level, &mut pathfinding, &NodePlacementSettings::default());
+
+        let ledge = pathfinding
+            .nodes
+            .iter()
+            .find(|node| node.position == Vec2::new(0.0, 0.0))
+            .unwrap();
+
+        assert_eq!(ledge.droppable_connections.len(), 1);
+
+        let landing_node = &pathfinding.nodes[ledge.droppable_connections[0].node_id];
+
+        assert_eq!(landing_node.position.y, -150.0);
+        // The agent is still moving sideways when it walks off, so it lands away from the wall
+        assert!(landing_node.position.x > 20.0);
+    }
+
+    #[test]
+    fn wall_jumps_climb_shafts() {
+        // A shaft too tall to jump out of. The right wall is capped by a ledge that sticks out
+        // over it, and the room at the top can only be reached by pushing off of the left wall.
+        let level = Level::from_polygons(
+            &[PolygonData {
+                points: vec![
+                    Vec2::new(-48.0, -150.0),
+                    Vec2::new(48.0, -150.0),
+                    Vec2::new(48.0, 100.0),
+                    Vec2::new(16.0, 100.0),
+                    Vec2::new(16.0, 116.0),
+                    Vec2::new(150.0, 116.0),
+                    Vec2::new(150.0, 200.0),
+                    Vec2::new(-48.0, 200.0),
+                ],
+                is_container: true,
+            }],
+            32.0,
+        )
+        .unwrap();
+
+        let mut pathfinding = PathfindingGraph::new(&DEFAULT_AGENT_PROFILE);
+        build_pathfinding_graph(&level, &mut pathfinding, &NodePlacementSettings::default());
+
+        assert!(pathfinding
+            .nodes
+            .iter()
+            .all(|node| !is_wall_node(node) || node.jumpable_connections.is_empty()));
+
+        let start = pathfinding
+            .nodes
+            .iter()
+            .position(|node| node.position == Vec2::new(-48.0, -150.0))
+            .unwrap();
+        let room_floor = pathfinding
+            .nodes
+            .iter()
+            .position(|node| node.position == Vec2::new(150.0, 116.0))
+            .unwrap();
+
+        // Walks the graph from the bottom of the shaft
+        let get_reached = |wall_jumps: bool| {
+            let mut reached = vec![false; pathfinding.nodes.len()];
+            let mut open = vec![start];
+            reached[start] = true;
+
+            while let Some(node_index) = open.pop() {
+                let node = &pathfinding.nodes[node_index];
+
+                let wall_jumpable_connections = if wall_jumps {
+                    node.wall_jumpable_connections.as_slice()
+                } else {
+                    &[]
+                };
+
+                for connection in node
+                    .walkable_connections
+                    .iter()
+                    .chain(&node.jumpable_connections)
+                    .chain(&node.droppable_connections)
+                    .chain(wall_jumpable_connections)
+                {
+                    if !reached[connection.node_id] {
+                        reached[connection.node_id] = true;
+                        open.push(connection.node_id);
+                    }
+                }
+            }
+
+            reached
+        };
+
+        assert!(!get_reached(false)[room_floor]);
+        assert!(get_reached(true)[room_floor]);
+    }
+
+    #[test]
+    fn shortcuts_connect_the_nodes_at_their_entrances() {
+        let level_data = LevelData::from_bytes(
+            br#"{
+                "polygons": [{ "points": [[-200, -50], [200, -50], [200, 50], [-200, 50]], "is_container": true }],
+                "shortcuts": [{ "entrances": [[-190, -45], [0, 45], [190, -45]] }]
+            }"#,
+        )
+        .unwrap();
+        let level = Level::from_level_data(&level_data, &get_test_tileset(), 32.0).unwrap();
+
+        let mut pathfinding = PathfindingGraph::new(&DEFAULT_AGENT_PROFILE);
+        build_pathfinding_graph(&level, &mut pathfinding, &NodePlacementSettings::default());
+
+        let left_node = pathfinding
+            .get_closest_node(Vec2::new(-190.0, -45.0))
+            .unwrap();
+        let right_node = pathfinding
+            .get_closest_node(Vec2::new(190.0, -45.0))
+            .unwrap();
+
+        let connection = pathfinding.nodes[left_node]
+            .shortcut_connections
+            .iter()
+            .find(|connection| connection.node_id == right_node)
+            .unwrap();
+
+        // Costs the length of the pipe, which goes up to the middle entrance and back down,
+        // plus getting from the nodes to the entrances
+        let pipe_length = 2.0 * Vec2::new(190.0, 90.0).length();
+        let entrance_dist = (Vec2::new(-190.0, -45.0) - pathfinding.nodes[left_node].position)
+            .length()
+            + (pathfinding.nodes[right_node].position - Vec2::new(190.0, -45.0)).length();
+        assert!((connection.dist - pipe_length - entrance_dist).abs() < 0.01);
+
+        let PathfindingGraphConnectionType::Shortcut { pipe } = &connection.connection_type else {
+            panic!("not a shortcut connection");
+        };
+        assert_eq!(pipe.first(), Some(&Vec2::new(-190.0, -45.0)));
+        assert_eq!(pipe.last(), Some(&Vec2::new(190.0, -45.0)));
+
+        // And the other way through the same pipe
+        assert!(pathfinding.nodes[right_node]
+            .shortcut_connections
+            .iter()
+            .any(|connection| connection.node_id == left_node));
+    }
+
+    #[test]
+    fn connections_cost_at_least_their_distance() {
+        for_each_shipped_graph(|_, pathfinding| {
+            // The A* heuristic is the straight-line distance to the goal, which is only
+            // admissible if no connection is cheaper than the distance it covers
+            for node in &pathfinding.nodes {
+                for connection in node.connections() {
+                    let other_node = &pathfinding.nodes[connection.node_id];
+                    let distance = (other_node.position - node.position).length();
+
+                    assert!(
+                        connection.dist >= distance - 0.01,
+                        "{:?} connection from {} to {} costs {}, but covers {}",
+                        connection.connection_type,
+                        node.id,
+                        other_node.id,
+                        connection.dist,
+                        distance
+                    );
+                }
+            }
+        });
+    }
+
+    /// Run with `cargo test --release bench_jumpable_connections -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_jumpable_connections() {
+        let level = get_large_test_level();
+
+        let mut reference_pathfinding = get_test_pathfinding(&level);
+        let mut pathfinding = get_test_pathfinding(&level);
+
+        let start = Instant::now();
+        make_jumpable_connections_reference(&mut reference_pathfinding, &level);
+        let reference_time = start.elapsed();
+
+        let start = Instant::now();
+        make_jumpable_connections(&mut pathfinding, &level);
+        let time = start.elapsed();
+
+        println!(
+            "{} nodes, {} lines: all pairs took {:?}, pruned and parallel took {:?} ({:.1}x)",
+            pathfinding.nodes.len(),
+            level
+                .polygons
+                .iter()
+                .map(|polygon| polygon.points.len() - 1)
+                .sum::<usize>(),
+            reference_time,
+            time,
+            reference_time.as_secs_f64() / time.as_secs_f64()
+        );
+
+        assert_eq!(get_jumps(&pathfinding), get_jumps(&reference_pathfinding));
+    }
+}
@@ -1,9 +1,8 @@
 use bevy::{
     app::{App, Plugin, Update},
-    core_pipeline::core_3d::graph::node,
     ecs::{
         component::Component,
-        schedule::IntoSystemConfigs,
+        schedule::{common_conditions::resource_exists, IntoSystemConfigs},
         system::{Query, Res},
     },
     gizmos::gizmos::Gizmos,
@@ -12,10 +11,14 @@ use bevy::{
     transform::components::Transform,
 };
 
-use crate::{s_move_goal_point, GizmosVisible, Physics, GRAVITY_STRENGTH};
+use crate::{level::Level, s_move_goal_point, GizmosVisible, Physics, GRAVITY_STRENGTH};
 
-use super::{a_star::find_path, pathfinding::Pathfinding};
+use super::{
+    d_star_lite::PathPlanner,
+    pathfinding::{Pathfinding, PathfindingGraph, PathfindingGraphConnectionType},
+};
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PathFollowingStrategy {
     CurrentNodeToNextNode,
@@ -25,12 +28,13 @@ pub enum PathFollowingStrategy {
     AgentToNextNode,
     AgentToNextNodeOffset,
     AgentToGoal,
+    AgentOffLedge,
     None,
 }
 
 pub const PLATFORMER_AI_AGENT_RADIUS: f32 = 8.0;
 
-const WANDER_MAX_SPEED: f32 = 3.0;
+pub const WANDER_MAX_SPEED: f32 = 3.0;
 // const PURSUE_MAX_SPEED: f32 = 5.0;
 // const ATTACK_MAX_SPEED: f32 = 7.0;
 
@@ -38,37 +42,158 @@ const WANDER_MAX_SPEED: f32 = 3.0;
 
 pub const PLATFORMER_AI_JUMP_FORCE: f32 = 8.0;
 
+pub const PLATFORMER_AI_NODE_SPACING: f32 = 20.0;
+
+/// How far an agent moves through a shortcut pipe each frame
+const SHORTCUT_SPEED: f32 = 6.0;
+
+/// The body and jump of an agent.
+/// Each profile gets its own pathfinding graph, since where an agent can go depends on both.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AgentProfile {
+    pub radius: f32,
+    pub jump_force: f32,
+    pub gravity: f32,
+    /// How far apart the pathfinding nodes along a surface are
+    pub node_spacing: f32,
+    /// Crawling agents cling to walls and ceilings like lizards, so every surface gets nodes
+    pub can_crawl: bool,
+}
+
+pub const DEFAULT_AGENT_PROFILE: AgentProfile = AgentProfile {
+    radius: PLATFORMER_AI_AGENT_RADIUS,
+    jump_force: PLATFORMER_AI_JUMP_FORCE,
+    gravity: GRAVITY_STRENGTH,
+    node_spacing: PLATFORMER_AI_NODE_SPACING,
+    can_crawl: false,
+};
+
+/// Every kind of agent in the game, `PlatformerAI::profile` is an index into this
+pub const AGENT_PROFILES: [AgentProfile; 4] = [
+    DEFAULT_AGENT_PROFILE,
+    // Small and light, jumps high and fits through small gaps
+    AgentProfile {
+        radius: 5.0,
+        jump_force: 8.5,
+        gravity: 0.45,
+        node_spacing: 14.0,
+        can_crawl: false,
+    },
+    // Big and heavy, can't jump as high
+    AgentProfile {
+        radius: 13.0,
+        jump_force: 8.5,
+        gravity: 0.6,
+        node_spacing: 28.0,
+        can_crawl: false,
+    },
+    // Crawls along walls and ceilings
+    AgentProfile {
+        radius: 7.0,
+        jump_force: 7.5,
+        gravity: 0.5,
+        node_spacing: 20.0,
+        can_crawl: true,
+    },
+];
+
 pub const ACCELERATION_SCALERS: (f32, f32) = (0.2, 0.4);
 
 pub struct PlatformerAIPlugin;
 
 impl Plugin for PlatformerAIPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, s_platformer_ai_movement.after(s_move_goal_point));
+        app.add_systems(
+            Update,
+            s_platformer_ai_movement
+                .after(s_move_goal_point)
+                .run_if(resource_exists::<Level>()),
+        );
     }
 }
 
 #[derive(Component)]
 pub struct PlatformerAI {
+    /// The index of the agent's profile in `AGENT_PROFILES`, and of its graph in `Pathfinding::graphs`
+    pub profile: usize,
     pub current_target_node: Option<usize>,
     pub jump_from_pos: Option<Vec2>,
     pub jump_to_pos: Option<Vec2>,
+    /// Set while the agent is inside of a shortcut pipe
+    pub shortcut_travel: Option<ShortcutTravel>,
+    /// The agent's path search, which is repaired as the agent and the goal move
+    pub path_planner: PathPlanner,
+}
+
+/// An agent's trip through a shortcut pipe
+pub struct ShortcutTravel {
+    /// The points the agent moves through, from where it went in to where it comes out
+    pub route: Vec<Vec2>,
+    /// How far along the route the agent is
+    pub distance: f32,
 }
 
 pub fn s_platformer_ai_movement(
     mut platformer_ai_query: Query<(&mut Transform, &mut Physics, &mut PlatformerAI)>,
     pathfinding: Res<Pathfinding>,
+    level: Res<Level>,
     gismo_visible: Res<GizmosVisible>,
     mut gizmos: Gizmos,
 ) {
     for (mut transform, mut physics, mut platformer_ai) in platformer_ai_query.iter_mut() {
-        let (move_dir, jump_velocity, jump_from_node, jump_to_node) = get_move_inputs(
-            pathfinding.as_ref(),
-            transform.translation.xy(),
-            &physics,
-            &mut gizmos,
-            gismo_visible.visible,
-        );
+        let graph = &pathfinding.graphs[platformer_ai.profile];
+        let gravity = graph.profile.gravity;
+        let crawling = graph.profile.can_crawl;
+
+        // Agents in a shortcut are hidden, and just follow the pipe until they come out the other end
+        if let Some(shortcut_travel) = &mut platformer_ai.shortcut_travel {
+            shortcut_travel.distance += SHORTCUT_SPEED;
+
+            let position =
+                match get_point_along_route(&shortcut_travel.route, shortcut_travel.distance) {
+                    Some(position) => position,
+                    None => {
+                        let exit_position = *shortcut_travel.route.last().unwrap();
+
+                        platformer_ai.shortcut_travel = None;
+
+                        exit_position
+                    }
+                };
+
+            // Come out falling, so the agent finds the surface next to the exit by itself
+            transform.translation = position.extend(0.0);
+            physics.prev_position = position;
+            physics.velocity = Vec2::ZERO;
+            physics.acceleration = Vec2::ZERO;
+            physics.normal = Vec2::ZERO;
+            physics.grounded = false;
+            physics.walled = 0;
+
+            continue;
+        }
+
+        let (move_dir, jump_velocity, jump_from_node, jump_to_node, shortcut_route) =
+            get_move_inputs(
+                &mut platformer_ai.path_planner,
+                graph,
+                &level,
+                // Only follow a path to the goal point while it's turned on
+                pathfinding.active.then_some(pathfinding.goal_position),
+                transform.translation.xy(),
+                &physics,
+                &mut gizmos,
+                gismo_visible.visible,
+            );
+
+        if let Some(shortcut_route) = shortcut_route {
+            platformer_ai.shortcut_travel = Some(ShortcutTravel {
+                route: shortcut_route,
+                distance: 0.0,
+            });
+
+            continue;
+        }
 
         if gismo_visible.visible {
             gizmos.line_2d(
@@ -83,7 +208,7 @@ pub fn s_platformer_ai_movement(
 
         apply_movement_acceleration(&mut physics, &move_dir, falling, no_move_dir);
 
-        apply_gravity_toward_normal(&mut physics, falling /*, player_move_off_wall*/);
+        apply_gravity_toward_normal(&mut physics, falling, gravity, crawling);
 
         // Jumping
         {
@@ -94,7 +219,7 @@ pub fn s_platformer_ai_movement(
                     // Jump
                     physics.velocity = jump_velocity;
                     physics.acceleration.x = 0.0;
-                    physics.acceleration.y = -GRAVITY_STRENGTH;
+                    physics.acceleration.y = -gravity;
                     physics.grounded = false;
                     physics.has_wall_jumped = false;
                     physics.walled = 0;
@@ -109,7 +234,7 @@ pub fn s_platformer_ai_movement(
                     // Wall jump
                     physics.velocity = jump_velocity;
                     physics.acceleration.x = 0.0;
-                    physics.acceleration.y = -GRAVITY_STRENGTH;
+                    physics.acceleration.y = -gravity;
                     physics.walled = 0;
                     physics.grounded = false;
                     physics.has_wall_jumped = true;
@@ -129,49 +254,94 @@ pub fn s_platformer_ai_movement(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn get_move_inputs(
-    pathfinding: &Pathfinding,
+    path_planner: &mut PathPlanner,
+    pathfinding: &PathfindingGraph,
+    level: &Level,
+    goal_position: Option<Vec2>,
     agent_position: Vec2,
     agent_physics: &Physics,
     gizmos: &mut Gizmos,
     gizmos_visible: bool,
-) -> (Vec2, Vec2, Option<Vec2>, Option<Vec2>) {
+) -> (Vec2, Vec2, Option<Vec2>, Option<Vec2>, Option<Vec<Vec2>>) {
     let mut move_dir = Vec2::ZERO;
     let mut jump_velocity = Vec2::ZERO;
     let mut jump_from_node = None;
     let mut jump_to_node = None;
+    let mut shortcut_route = None;
+
+    let Some(goal_position) = goal_position else {
+        return (
+            move_dir,
+            jump_velocity,
+            jump_from_node,
+            jump_to_node,
+            shortcut_route,
+        );
+    };
 
-    let path = find_path(&pathfinding, agent_position);
+    let path = path_planner.plan_path(pathfinding, level, goal_position, agent_position);
 
     if let Some(path) = path {
         if gizmos_visible {
             let mut prev_pos = agent_position;
-            for i in 0..path.len() {
-                gizmos.circle_2d(path[i].position, 5.0, Color::GREEN);
-                gizmos.line_2d(prev_pos, path[i].position, Color::GREEN);
+            for path_node in path.iter() {
+                gizmos.circle_2d(path_node.position, 5.0, Color::GREEN);
+                gizmos.line_2d(prev_pos, path_node.position, Color::GREEN);
 
-                prev_pos = path[i].position;
+                prev_pos = path_node.position;
             }
-
-            gizmos.line_2d(prev_pos, pathfinding.goal_position, Color::GREEN);
         }
 
-        if path.len() > 1 {
+        // The goal position at the end of the path isn't a node the agent can stand on,
+        // so only the nodes before it are followed
+        let node_ids: Vec<usize> = path.iter().filter_map(|path_node| path_node.id).collect();
+
+        if node_ids.len() > 1 {
+            let current_node = &pathfinding.nodes[node_ids[0]];
+            let next_node = &pathfinding.nodes[node_ids[1]];
+
             let offset_current_node =
-                path[0].position + pathfinding.nodes[path[0].id].normal * agent_physics.radius;
+                current_node.position + current_node.normal * agent_physics.radius;
             let offset_next_node: Vec2 =
-                path[1].position + pathfinding.nodes[path[1].id].normal * agent_physics.radius;
+                next_node.position + next_node.normal * agent_physics.radius;
 
             let agent_on_wall = agent_physics.normal.y > -0.01;
 
-            let corner_is_external = pathfinding.nodes[path[0].id].is_external_corner;
+            let corner_is_external = current_node.is_external_corner;
 
             let current_node_is_corner = corner_is_external.is_some();
 
-            let is_jumpable_connection = pathfinding.nodes[path[0].id]
+            let is_jumpable_connection = current_node
                 .jumpable_connections
                 .iter()
-                .any(|jumpable_connection| jumpable_connection.node_id == path[1].id);
+                .any(|jumpable_connection| jumpable_connection.node_id == next_node.id);
+
+            let is_wall_jumpable_connection = current_node
+                .wall_jumpable_connections
+                .iter()
+                .any(|wall_jumpable_connection| wall_jumpable_connection.node_id == next_node.id);
+
+            let shortcut_pipe =
+                current_node
+                    .shortcut_connections
+                    .iter()
+                    .find_map(
+                        |shortcut_connection| match &shortcut_connection.connection_type {
+                            PathfindingGraphConnectionType::Shortcut { pipe }
+                                if shortcut_connection.node_id == next_node.id =>
+                            {
+                                Some(pipe)
+                            }
+                            _ => None,
+                        },
+                    );
+
+            let is_droppable_connection = current_node
+                .droppable_connections
+                .iter()
+                .any(|droppable_connection| droppable_connection.node_id == next_node.id);
 
             let falling = agent_physics.normal.length_squared() <= 0.0;
 
@@ -184,7 +354,7 @@ fn get_move_inputs(
                     let agent_on_other_side_next_frame = agent_on_other_side_next_frame(
                         agent_position,
                         agent_physics.velocity,
-                        path[0].position,
+                        current_node.position,
                         agent_on_wall,
                     );
 
@@ -198,6 +368,41 @@ fn get_move_inputs(
                         println!("Test 2");
                         PathFollowingStrategy::AgentToCurrentNodeOffset
                     };
+                }
+                // Agent wall jumping
+                else if is_wall_jumpable_connection {
+                    let agent_at_current_node = (offset_current_node - agent_position)
+                        .length_squared()
+                        <= agent_physics.radius * agent_physics.radius;
+
+                    // Get onto the wall at the node first, the jump has to push off of it
+                    path_following_strategy = if agent_physics.walled != 0 && agent_at_current_node
+                    {
+                        PathFollowingStrategy::AgentToNextNodeOffset
+                    } else {
+                        PathFollowingStrategy::AgentToCurrentNodeOffset
+                    };
+                }
+                // Agent going into a shortcut
+                else if let Some(shortcut_pipe) = shortcut_pipe {
+                    path_following_strategy = PathFollowingStrategy::AgentToCurrentNodeOffset;
+
+                    let agent_at_current_node = (offset_current_node - agent_position)
+                        .length_squared()
+                        <= agent_physics.radius * agent_physics.radius;
+
+                    // Go in once the agent gets to the entrance, and come out next to the node at the exit
+                    if agent_at_current_node {
+                        let mut route = vec![agent_position];
+                        route.extend_from_slice(shortcut_pipe);
+                        route.push(offset_next_node);
+
+                        shortcut_route = Some(route);
+                    }
+                }
+                // Agent dropping off a ledge
+                else if is_droppable_connection {
+                    path_following_strategy = PathFollowingStrategy::AgentOffLedge;
                 } else {
                     // Non-jumping corner
                     if current_node_is_corner {
@@ -229,45 +434,79 @@ fn get_move_inputs(
             }
 
             move_dir = match path_following_strategy {
-                PathFollowingStrategy::CurrentNodeToNextNode => path[1].position - path[0].position,
+                PathFollowingStrategy::CurrentNodeToNextNode => {
+                    next_node.position - current_node.position
+                }
                 PathFollowingStrategy::CurrentNodeOffsetToNextNodeOffset => {
                     offset_next_node - offset_current_node
                 }
-                PathFollowingStrategy::AgentToCurrentNode => path[0].position - agent_position,
+                PathFollowingStrategy::AgentToCurrentNode => current_node.position - agent_position,
                 PathFollowingStrategy::AgentToCurrentNodeOffset => {
                     offset_current_node - agent_position
                 }
-                PathFollowingStrategy::AgentToNextNode => path[1].position - agent_position,
+                PathFollowingStrategy::AgentToNextNode => next_node.position - agent_position,
                 PathFollowingStrategy::AgentToNextNodeOffset => offset_next_node - agent_position,
-                PathFollowingStrategy::AgentToGoal => pathfinding.goal_position - agent_position,
+                PathFollowingStrategy::AgentToGoal => goal_position - agent_position,
+                PathFollowingStrategy::AgentOffLedge => {
+                    // Walk straight off the side of the ledge that drops away
+                    Vec2::new(current_node.normal.x.signum(), 0.0)
+                }
                 PathFollowingStrategy::None => Vec2::ZERO,
-                _ => Vec2::ZERO,
             }
             .normalize_or_zero();
 
             // Jumping
-            if path_following_strategy == PathFollowingStrategy::AgentToNextNodeOffset
-                || path_following_strategy == PathFollowingStrategy::AgentToNextNode
+            if (path_following_strategy == PathFollowingStrategy::AgentToNextNodeOffset
+                || path_following_strategy == PathFollowingStrategy::AgentToNextNode)
+                && (is_jumpable_connection || is_wall_jumpable_connection)
             {
-                if is_jumpable_connection {
-                    let node_position_delta = path[1].position - path[0].position;
-                    let gravity_acceleration = Vec2::new(0.0, -GRAVITY_STRENGTH);
-                    let jump_time = 1.0
-                        * (4.0 * node_position_delta.dot(node_position_delta)
-                            / gravity_acceleration.dot(gravity_acceleration))
-                        .sqrt()
-                        .sqrt();
-                    jump_velocity =
-                        node_position_delta / jump_time - gravity_acceleration * jump_time / 2.0;
-
-                    jump_from_node = Some(offset_current_node);
-                    jump_to_node = Some(offset_next_node);
-                }
+                // Wall jumps are launched from the agent's position on the wall, which is where
+                // the graph checked they push away from it
+                let node_position_delta = if is_wall_jumpable_connection {
+                    offset_next_node - offset_current_node
+                } else {
+                    next_node.position - current_node.position
+                };
+                let gravity_acceleration = Vec2::new(0.0, -pathfinding.profile.gravity);
+                let jump_time = 1.0
+                    * (4.0 * node_position_delta.dot(node_position_delta)
+                        / gravity_acceleration.dot(gravity_acceleration))
+                    .sqrt()
+                    .sqrt();
+                jump_velocity =
+                    node_position_delta / jump_time - gravity_acceleration * jump_time / 2.0;
+
+                jump_from_node = Some(offset_current_node);
+                jump_to_node = Some(offset_next_node);
             }
         }
     }
 
-    (move_dir, jump_velocity, jump_from_node, jump_to_node)
+    (
+        move_dir,
+        jump_velocity,
+        jump_from_node,
+        jump_to_node,
+        shortcut_route,
+    )
+}
+
+/// The point `distance` along a route of points, or nothing once it's past the end
+fn get_point_along_route(route: &[Vec2], distance: f32) -> Option<Vec2> {
+    let mut distance_left = distance;
+
+    for points in route.windows(2) {
+        let segment = points[1] - points[0];
+        let segment_length = segment.length();
+
+        if distance_left < segment_length {
+            return Some(points[0] + segment * (distance_left / segment_length));
+        }
+
+        distance_left -= segment_length;
+    }
+
+    None
 }
 
 fn apply_movement_acceleration(
@@ -304,14 +543,22 @@ fn apply_movement_acceleration(
 fn apply_gravity_toward_normal(
     physics: &mut Physics,
     falling: bool,
+    gravity: f32,
+    crawling: bool,
     // player_move_off_wall: bool,
 ) {
     if
     /*player_move_off_wall || */
     falling {
-        physics.acceleration.y = -GRAVITY_STRENGTH;
+        physics.acceleration.y = -gravity;
     } else {
-        let gravity_normal_dir = physics.normal * GRAVITY_STRENGTH;
+        // Crawling agents can't push themselves off of the surface they're on
+        if crawling {
+            let away_from_surface = physics.acceleration.dot(physics.normal).min(0.0);
+            physics.acceleration -= physics.normal * away_from_surface;
+        }
+
+        let gravity_normal_dir = physics.normal * gravity;
         physics.acceleration += gravity_normal_dir;
     }
 }
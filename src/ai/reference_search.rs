@@ -0,0 +1,700 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use bevy::math::Vec2;
+
+use crate::level::Level;
+
+use super::{
+    a_star::{fill_reachable_nodes, PathNode},
+    pathfinding::PathfindingGraph,
+};
+
+/// Everything a path search works in, indexed by node id.
+/// It's kept between searches, so finding a path every frame doesn't allocate.
+#[derive(Default)]
+pub struct PathSearch {
+    /// The cost of the cheapest way found to each node so far
+    g_costs: Vec<f32>,
+    /// The node each node is reached from on the cheapest way to it
+    parents: Vec<Option<usize>>,
+    open_list: BinaryHeap<OpenNode>,
+    /// The nodes and what it costs to get to them from the node being expanded
+    neighbours: Vec<(usize, f32)>,
+    nodes_by_distance: Vec<(usize, f32)>,
+    start_connections: Vec<usize>,
+    goal_connections: Vec<usize>,
+}
+
+/// Finds the cheapest path from the start position to the goal position.
+/// Both positions are spliced into the graph as temporary nodes for the search,
+/// so the path is made of the graph nodes between them, followed by the goal position.
+/// Agents use a `PathPlanner` instead, which repairs its last search rather than starting over,
+/// so this is only built for the tests, as the search the planner is checked and timed against.
+pub fn find_path(
+    path_search: &mut PathSearch,
+    pathfinding: &PathfindingGraph,
+    level: &Level,
+    goal_position: Vec2,
+    start_position: Vec2,
+) -> Option<Vec<PathNode>> {
+    let nodes = &pathfinding.nodes;
+
+    // The temporary nodes come after the nodes of the graph
+    let start_id = nodes.len();
+    let goal_id = nodes.len() + 1;
+
+    fill_reachable_nodes(
+        pathfinding,
+        level,
+        start_position,
+        &mut path_search.nodes_by_distance,
+        &mut path_search.start_connections,
+    );
+    fill_reachable_nodes(
+        pathfinding,
+        level,
+        goal_position,
+        &mut path_search.nodes_by_distance,
+        &mut path_search.goal_connections,
+    );
+
+    if path_search.start_connections.is_empty() || path_search.goal_connections.is_empty() {
+        return None;
+    }
+
+    let PathSearch {
+        g_costs,
+        parents,
+        open_list,
+        neighbours,
+        start_connections,
+        goal_connections,
+        ..
+    } = path_search;
+
+    let node_count = nodes.len() + 2;
+
+    g_costs.clear();
+    g_costs.resize(node_count, f32::MAX);
+    parents.clear();
+    parents.resize(node_count, None);
+    open_list.clear();
+
+    let get_position = |id: usize| {
+        if id == start_id {
+            start_position
+        } else if id == goal_id {
+            goal_position
+        } else {
+            nodes[id].position
+        }
+    };
+
+    // Add the start node to the open list
+    g_costs[start_id] = 0.0;
+    open_list.push(OpenNode {
+        id: start_id,
+        g_cost: 0.0,
+        f_cost: (goal_position - start_position).length(),
+        h_cost: (goal_position - start_position).length(),
+    });
+
+    // Get the node with the lowest f-cost
+    while let Some(current_node) = open_list.pop() {
+        let current_id = current_node.id;
+
+        // A node is pushed again whenever a cheaper way to it is found,
+        // so this entry is out of date if there's a cheaper one
+        if current_node.g_cost > g_costs[current_id] {
+            continue;
+        }
+
+        // If the current node is the goal, reconstruct the path
+        if current_id == goal_id {
+            let mut path: Vec<PathNode> = vec![PathNode::new(None, goal_position)];
+
+            let mut parent = parents[goal_id];
+            while let Some(parent_id) = parent {
+                // The temporary start node isn't part of the graph
+                if parent_id != start_id {
+                    path.push(PathNode::new(Some(parent_id), nodes[parent_id].position));
+                }
+
+                parent = parents[parent_id];
+            }
+
+            path.reverse();
+
+            return Some(path);
+        }
+
+        // Everything the current node is connected to, including the temporary nodes
+        neighbours.clear();
+
+        if current_id == start_id {
+            neighbours.extend(start_connections.iter().map(|node_id| {
+                (
+                    *node_id,
+                    (nodes[*node_id].position - start_position).length(),
+                )
+            }));
+        } else {
+            let graph_node = &nodes[current_id];
+
+            neighbours.extend(
+                graph_node
+                    .connections()
+                    .map(|connection| (connection.node_id, connection.dist)),
+            );
+
+            if goal_connections.contains(&current_id) {
+                neighbours.push((goal_id, (goal_position - graph_node.position).length()));
+            }
+        }
+
+        for (node_id, dist) in neighbours.iter().copied() {
+            // Set the g-cost to the distance to the start node
+            let g_cost = current_node.g_cost + dist;
+
+            // Only a cheaper way to the node is worth expanding it again
+            if g_cost >= g_costs[node_id] {
+                continue;
+            }
+
+            g_costs[node_id] = g_cost;
+            parents[node_id] = Some(current_id);
+
+            // Set the h-cost to the distance to the goal. It's never more than what getting
+            // to the goal costs, since no connection costs less than the distance it covers,
+            // so the first path to the goal that's taken out of the open list is the cheapest.
+            let h_cost = (goal_position - get_position(node_id)).length();
+
+            open_list.push(OpenNode {
+                id: node_id,
+                g_cost,
+                f_cost: g_cost + h_cost,
+                h_cost,
+            });
+        }
+    }
+
+    // If the open list is empty, there is no path
+    None
+}
+
+/// A node in the open list, waiting to be expanded
+struct OpenNode {
+    id: usize,
+    g_cost: f32,
+    f_cost: f32,
+    h_cost: f32,
+}
+
+impl Ord for OpenNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.f_cost.partial_cmp(&other.f_cost) {
+            Some(Ordering::Equal) => self
+                .h_cost
+                .partial_cmp(&other.h_cost)
+                .unwrap_or(Ordering::Equal),
+            Some(order) => order.reverse(),
+            None => Ordering::Equal,
+        }
+    }
+}
+
+impl Eq for OpenNode {}
+
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OpenNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+mod tests {
+    use std::time::Instant;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        ai::{
+            a_star::get_reachable_nodes,
+            pathfinding::{
+                build_pathfinding_graph, NodePlacementSettings, PathfindingGraphConnection,
+                PathfindingGraphConnectionType, PathfindingGraphNode,
+            },
+            platformer_ai::DEFAULT_AGENT_PROFILE,
+            test_utils::{for_each_shipped_graph, get_level, get_path_cost},
+        },
+        level::loader::PolygonData,
+    };
+
+    /// How far the random graphs reach from the middle of their room
+    const RANDOM_GRAPH_SIZE: f32 = 1000.0;
+
+    /// The original search, which clones the connections of every node it opens and keeps its
+    /// closed list in a `Vec`. It's what the search is timed against.
+    fn find_path_reference(
+        pathfinding: &PathfindingGraph,
+        level: &Level,
+        goal_position: Vec2,
+        start_position: Vec2,
+    ) -> Option<Vec<PathNode>> {
+        // The temporary nodes come after the nodes of the graph
+        let start_id = pathfinding.nodes.len();
+        let goal_id = pathfinding.nodes.len() + 1;
+
+        let start_connections = get_reachable_nodes(pathfinding, level, start_position);
+        let goal_connections = get_reachable_nodes(pathfinding, level, goal_position);
+
+        if start_connections.is_empty() || goal_connections.is_empty() {
+            return None;
+        }
+
+        // Gets the A* node for an id, including the temporary nodes and the connections to them
+        let get_a_star_node = |id: usize| {
+            if id == start_id {
+                return AStarNode {
+                    position: start_position,
+                    id,
+                    connections: get_temporary_connections(
+                        pathfinding,
+                        start_position,
+                        &start_connections,
+                    ),
+                    g_cost: 0.0,
+                    h_cost: 0.0,
+                    parent: None,
+                };
+            }
+
+            if id == goal_id {
+                return AStarNode {
+                    position: goal_position,
+                    id,
+                    connections: vec![],
+                    g_cost: 0.0,
+                    h_cost: 0.0,
+                    parent: None,
+                };
+            }
+
+            let graph_node = &pathfinding.nodes[id];
+            let mut a_star_node = AStarNode::new(graph_node);
+
+            if goal_connections.contains(&id) {
+                a_star_node.connections.push(PathfindingGraphConnection {
+                    node_id: goal_id,
+                    dist: (goal_position - graph_node.position).length(),
+                    connection_type: PathfindingGraphConnectionType::Walkable,
+                    effort: 0.0,
+                });
+            }
+
+            a_star_node
+        };
+
+        let mut open_list: BinaryHeap<AStarNode> = BinaryHeap::new();
+        let mut closed_list: Vec<AStarNode> = vec![];
+
+        // Get the start node
+        let mut start_node = get_a_star_node(start_id);
+
+        // Set the h-cost to the distance to the goal
+        start_node.h_cost = (goal_position - start_position).length();
+
+        // Add the start node to the open list
+        open_list.push(start_node);
+
+        loop {
+            // If the open list is empty, there is no path
+            if open_list.is_empty() {
+                return None;
+            }
+
+            // Get the node with the lowest f-cost
+            let current_node = open_list.pop().unwrap();
+
+            // If the current node is the goal, reconstruct the path
+            if current_node.id == goal_id {
+                let mut path: Vec<PathNode> = vec![];
+
+                let mut current_node = current_node;
+                while let Some(parent_id) = current_node.parent {
+                    let parent_node = closed_list.iter().find(|n| n.id == parent_id).unwrap();
+
+                    // The temporary start node isn't part of the graph
+                    if parent_id != start_id {
+                        path.push(PathNode::new(Some(parent_id), parent_node.position));
+                    }
+
+                    current_node = parent_node.clone();
+                }
+
+                path.reverse();
+
+                return Some(path);
+            }
+
+            // If the node is in the closed list, skip it
+            if closed_list.iter().any(|n| n.id == current_node.id) {
+                continue;
+            }
+
+            // Add the current node to the closed list
+            closed_list.push(current_node.clone());
+
+            // For each connection of the current node
+            for connection in current_node.connections.iter() {
+                let mut new_node = get_a_star_node(connection.node_id);
+
+                // If the new node is the goal, set the is_goal flag
+                if new_node.id != goal_id {
+                    // Set the g-cost to the distance to the start node
+                    new_node.g_cost = connection.dist + current_node.g_cost;
+
+                    // Set the h-cost to the distance to the goal
+                    new_node.h_cost = (goal_position - new_node.position).length();
+                }
+
+                // Set the parent of the new node
+                new_node.parent = Some(current_node.id);
+
+                open_list.push(new_node);
+            }
+        }
+    }
+
+    fn get_temporary_connections(
+        pathfinding: &PathfindingGraph,
+        position: Vec2,
+        node_ids: &[usize],
+    ) -> Vec<PathfindingGraphConnection> {
+        node_ids
+            .iter()
+            .map(|node_id| PathfindingGraphConnection {
+                node_id: *node_id,
+                dist: (pathfinding.nodes[*node_id].position - position).length(),
+                connection_type: PathfindingGraphConnectionType::Walkable,
+                effort: 0.0,
+            })
+            .collect()
+    }
+
+    #[derive(Clone, Debug)]
+    struct AStarNode {
+        position: Vec2,
+        id: usize,
+        connections: Vec<PathfindingGraphConnection>,
+        g_cost: f32,
+        h_cost: f32,
+        parent: Option<usize>,
+    }
+
+    impl AStarNode {
+        fn new(graph_node: &PathfindingGraphNode) -> AStarNode {
+            let connections = graph_node.connections().cloned().collect();
+
+            AStarNode {
+                position: graph_node.position,
+                id: graph_node.id,
+                connections,
+                g_cost: 0.0,
+                h_cost: 0.0,
+                parent: None,
+            }
+        }
+
+        fn get_f_cost(&self) -> f32 {
+            self.g_cost + self.h_cost
+        }
+    }
+
+    impl Ord for AStarNode {
+        fn cmp(&self, other: &Self) -> Ordering {
+            let self_f_cost = self.get_f_cost();
+            let other_f_cost = other.get_f_cost();
+
+            match self_f_cost.partial_cmp(&other_f_cost) {
+                Some(Ordering::Equal) => self
+                    .h_cost
+                    .partial_cmp(&other.h_cost)
+                    .unwrap_or(Ordering::Equal),
+                Some(order) => order.reverse(),
+                None => Ordering::Equal,
+            }
+        }
+    }
+
+    impl Eq for AStarNode {}
+
+    impl PartialOrd for AStarNode {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl PartialEq for AStarNode {
+        fn eq(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+    }
+
+    /// Pairs of places an agent could be, spread over the whole graph
+    fn get_queries(pathfinding: &PathfindingGraph) -> Vec<(Vec2, Vec2)> {
+        let agent_positions: Vec<Vec2> = pathfinding
+            .nodes
+            .iter()
+            .step_by(7)
+            .map(|node| node.position + node.normal * pathfinding.profile.radius)
+            .collect();
+
+        agent_positions
+            .iter()
+            .flat_map(|start| agent_positions.iter().map(|goal| (*start, *goal)))
+            .collect()
+    }
+
+    /// An empty room with nodes scattered around it and connected at random.
+    /// Like in a level's graph, no connection costs less than the distance it covers.
+    fn get_random_graph(
+        rng: &mut StdRng,
+        node_count: usize,
+        connection_count: usize,
+    ) -> (Level, PathfindingGraph) {
+        let level = Level::from_polygons(
+            &[PolygonData {
+                points: vec![
+                    Vec2::new(-RANDOM_GRAPH_SIZE, -RANDOM_GRAPH_SIZE),
+                    Vec2::new(RANDOM_GRAPH_SIZE, -RANDOM_GRAPH_SIZE),
+                    Vec2::new(RANDOM_GRAPH_SIZE, RANDOM_GRAPH_SIZE),
+                    Vec2::new(-RANDOM_GRAPH_SIZE, RANDOM_GRAPH_SIZE),
+                ],
+                is_container: true,
+            }],
+            32.0,
+        )
+        .unwrap();
+
+        let mut pathfinding = PathfindingGraph::new(&DEFAULT_AGENT_PROFILE);
+
+        for id in 0..node_count {
+            // Without a normal, every position is on the open side of the node
+            pathfinding.nodes.push(PathfindingGraphNode {
+                id,
+                position: get_random_position(rng),
+                polygon_index: 0,
+                line_indicies: vec![],
+                walkable_connections: vec![],
+                jumpable_connections: vec![],
+                droppable_connections: vec![],
+                wall_jumpable_connections: vec![],
+                shortcut_connections: vec![],
+                normal: Vec2::ZERO,
+                is_corner: false,
+                is_external_corner: None,
+            });
+        }
+
+        for _ in 0..connection_count {
+            let from = rng.gen_range(0..node_count);
+            let to = rng.gen_range(0..node_count);
+
+            let distance =
+                (pathfinding.nodes[to].position - pathfinding.nodes[from].position).length();
+
+            pathfinding.nodes[from]
+                .walkable_connections
+                .push(PathfindingGraphConnection {
+                    node_id: to,
+                    dist: distance * rng.gen_range(1.0..3.0),
+                    connection_type: PathfindingGraphConnectionType::Walkable,
+                    effort: 0.0,
+                });
+        }
+
+        (level, pathfinding)
+    }
+
+    fn get_random_position(rng: &mut StdRng) -> Vec2 {
+        let size = RANDOM_GRAPH_SIZE - 100.0;
+
+        Vec2::new(rng.gen_range(-size..size), rng.gen_range(-size..size))
+    }
+
+    /// The cost of the cheapest path from the start position to the goal position, found by
+    /// settling every node in order of its cost, with no heuristic and no heap to get wrong
+    fn get_cheapest_cost(
+        pathfinding: &PathfindingGraph,
+        level: &Level,
+        start_position: Vec2,
+        goal_position: Vec2,
+    ) -> Option<f32> {
+        let node_count = pathfinding.nodes.len();
+        let goal_connections = get_reachable_nodes(pathfinding, level, goal_position);
+
+        let mut costs = vec![f32::INFINITY; node_count];
+        let mut settled = vec![false; node_count];
+
+        for node_id in get_reachable_nodes(pathfinding, level, start_position) {
+            costs[node_id] = (pathfinding.nodes[node_id].position - start_position).length();
+        }
+
+        let mut cheapest_cost = f32::INFINITY;
+
+        // The cheapest node that isn't settled yet always costs its cheapest
+        while let Some(current_id) = (0..node_count)
+            .filter(|node_id| !settled[*node_id] && costs[*node_id].is_finite())
+            .min_by(|a, b| costs[*a].total_cmp(&costs[*b]))
+        {
+            settled[current_id] = true;
+
+            if goal_connections.contains(&current_id) {
+                let goal_cost = costs[current_id]
+                    + (goal_position - pathfinding.nodes[current_id].position).length();
+                cheapest_cost = cheapest_cost.min(goal_cost);
+            }
+
+            for connection in pathfinding.nodes[current_id].connections() {
+                let cost = costs[current_id] + connection.dist;
+
+                if cost < costs[connection.node_id] {
+                    costs[connection.node_id] = cost;
+                }
+            }
+        }
+
+        cheapest_cost.is_finite().then_some(cheapest_cost)
+    }
+
+    fn check_against_dijkstra(
+        path_search: &mut PathSearch,
+        pathfinding: &PathfindingGraph,
+        level: &Level,
+        start_position: Vec2,
+        goal_position: Vec2,
+    ) {
+        let path = find_path(
+            path_search,
+            pathfinding,
+            level,
+            goal_position,
+            start_position,
+        );
+        let cheapest_cost = get_cheapest_cost(pathfinding, level, start_position, goal_position);
+
+        match (path, cheapest_cost) {
+            (Some(path), Some(cheapest_cost)) => {
+                let cost = get_path_cost(pathfinding, level, start_position, goal_position, &path);
+
+                assert!(
+                    (cost - cheapest_cost).abs() <= cheapest_cost * 1e-5 + 1e-3,
+                    "from {} to {}: the path costs {}, but the cheapest costs {}",
+                    start_position,
+                    goal_position,
+                    cost,
+                    cheapest_cost
+                );
+            }
+            (None, None) => {}
+            (path, cheapest_cost) => panic!(
+                "from {} to {}: found a path: {}, but the cheapest costs {:?}",
+                start_position,
+                goal_position,
+                path.is_some(),
+                cheapest_cost
+            ),
+        }
+    }
+
+    #[test]
+    fn find_path_is_as_cheap_as_dijkstra_on_random_graphs() {
+        let mut rng = StdRng::seed_from_u64(24);
+        let mut path_search = PathSearch::default();
+
+        for _ in 0..200 {
+            let node_count = rng.gen_range(2..40);
+            let connection_count = rng.gen_range(0..node_count * 3);
+            let (level, pathfinding) = get_random_graph(&mut rng, node_count, connection_count);
+
+            for _ in 0..10 {
+                let start_position = get_random_position(&mut rng);
+                let goal_position = get_random_position(&mut rng);
+
+                check_against_dijkstra(
+                    &mut path_search,
+                    &pathfinding,
+                    &level,
+                    start_position,
+                    goal_position,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn find_path_is_as_cheap_as_dijkstra_on_shipped_levels() {
+        // The same context is used for every query, like it is in game
+        let mut path_search = PathSearch::default();
+
+        for_each_shipped_graph(|level, pathfinding| {
+            // Every graph is checked, so a share of the queries is enough to cover them
+            for (start_position, goal_position) in get_queries(pathfinding).into_iter().step_by(5) {
+                check_against_dijkstra(
+                    &mut path_search,
+                    pathfinding,
+                    level,
+                    start_position,
+                    goal_position,
+                );
+            }
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_find_path() {
+        let level = get_level(include_bytes!("../../assets/level.json"));
+
+        let mut pathfinding = PathfindingGraph::new(&DEFAULT_AGENT_PROFILE);
+        build_pathfinding_graph(&level, &mut pathfinding, &NodePlacementSettings::default());
+
+        let queries = get_queries(&pathfinding);
+
+        let start = Instant::now();
+        for (start_position, goal_position) in &queries {
+            find_path_reference(&pathfinding, &level, *goal_position, *start_position);
+        }
+        let reference_time = start.elapsed();
+
+        let mut path_search = PathSearch::default();
+
+        let start = Instant::now();
+        for (start_position, goal_position) in &queries {
+            find_path(
+                &mut path_search,
+                &pathfinding,
+                &level,
+                *goal_position,
+                *start_position,
+            );
+        }
+        let time = start.elapsed();
+
+        println!(
+            "{} nodes, {} queries: original took {:?} per query, indexed took {:?} per query ({:.1}x)",
+            pathfinding.nodes.len(),
+            queries.len(),
+            reference_time / queries.len() as u32,
+            time / queries.len() as u32,
+            reference_time.as_secs_f64() / time.as_secs_f64()
+        );
+    }
+}
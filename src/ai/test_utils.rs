@@ -0,0 +1,75 @@
+use bevy::math::Vec2;
+
+use crate::level::{loader::LevelData, tileset::Tileset, Level};
+
+use super::{
+    a_star::{get_reachable_nodes, PathNode},
+    pathfinding::{build_pathfinding_graph, NodePlacementSettings, PathfindingGraph},
+    platformer_ai::AGENT_PROFILES,
+};
+
+/// The levels that come with the game
+const SHIPPED_LEVELS: [&[u8]; 2] = [
+    include_bytes!("../../assets/level.json"),
+    include_bytes!("../../assets/cave.json"),
+];
+
+pub fn get_level(level_bytes: &[u8]) -> Level {
+    let level_data = LevelData::from_bytes(level_bytes).unwrap();
+    let tileset = Tileset::from_bytes(include_bytes!("../../assets/tiles.tileset.json")).unwrap();
+
+    Level::from_level_data(&level_data, &tileset, 32.0).unwrap()
+}
+
+/// Builds the graph of every agent profile on every shipped level, like the game does,
+/// and hands each one to `f` along with its level
+pub fn for_each_shipped_graph(mut f: impl FnMut(&Level, &PathfindingGraph)) {
+    for level_bytes in SHIPPED_LEVELS {
+        let level = get_level(level_bytes);
+
+        for profile in &AGENT_PROFILES {
+            let mut pathfinding = PathfindingGraph::new(profile);
+            build_pathfinding_graph(&level, &mut pathfinding, &NodePlacementSettings::default());
+
+            f(&level, &pathfinding);
+        }
+    }
+}
+
+/// What following a path from the start position costs, checking that every step of it
+/// is one the agent can take
+pub fn get_path_cost(
+    pathfinding: &PathfindingGraph,
+    level: &Level,
+    start_position: Vec2,
+    goal_position: Vec2,
+    path: &[PathNode],
+) -> f32 {
+    let (goal, path) = path.split_last().expect("the path is empty");
+    assert_eq!(goal.id, None);
+    assert_eq!(goal.position, goal_position);
+
+    let node_ids: Vec<usize> = path
+        .iter()
+        .map(|path_node| path_node.id.expect("a node without an id before the goal"))
+        .collect();
+
+    let first_node_id = *node_ids.first().expect("the path has no nodes");
+    let last_node_id = *node_ids.last().unwrap();
+
+    assert!(get_reachable_nodes(pathfinding, level, start_position).contains(&first_node_id));
+    assert!(get_reachable_nodes(pathfinding, level, goal_position).contains(&last_node_id));
+
+    let mut cost = (pathfinding.nodes[first_node_id].position - start_position).length();
+
+    for ids in node_ids.windows(2) {
+        cost += pathfinding.nodes[ids[0]]
+            .connections()
+            .filter(|connection| connection.node_id == ids[1])
+            .map(|connection| connection.dist)
+            .min_by(f32::total_cmp)
+            .expect("the path follows a connection that doesn't exist");
+    }
+
+    cost + (goal_position - pathfinding.nodes[last_node_id].position).length()
+}
@@ -1,137 +1,153 @@
 use bevy::{
     app::{App, Plugin, Update},
     ecs::{
-        schedule::IntoSystemConfigs,
+        schedule::{common_conditions::resource_exists, IntoSystemConfigs},
         system::{Query, Res},
     },
-    gizmos::gizmos::Gizmos,
     math::{Vec2, Vec3Swizzles},
     transform::components::Transform,
 };
 
 use crate::{
-    ai::platformer_ai::{s_platformer_ai_movement, PlatformerAI},
+    ai::{
+        pathfinding::Pathfinding,
+        platformer_ai::{s_platformer_ai_movement, PlatformerAI},
+    },
     level::Level,
-    utils::{line_intersect, side_of_line_detection},
+    utils::side_of_line_detection,
     Physics,
 };
 
+/// How far away a crawling agent can still hold on to a surface, relative to its radius
+const CRAWL_GRIP_RADIUS_SCALE: f32 = 2.0;
+
 pub struct CollisionPlugin;
 
 impl Plugin for CollisionPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, s_collision.after(s_platformer_ai_movement));
+        app.add_systems(
+            Update,
+            s_collision
+                .after(s_platformer_ai_movement)
+                .run_if(resource_exists::<Level>()),
+        );
     }
 }
 
 pub fn s_collision(
     mut entity_query: Query<(&mut Transform, &mut Physics, &mut PlatformerAI)>,
     level: Res<Level>,
-    mut gizmos: Gizmos,
+    pathfinding: Res<Pathfinding>,
 ) {
-    if let Ok((mut transform, mut physics, mut platformer_ai)) = entity_query.get_single_mut() {
+    for (mut transform, mut physics, mut platformer_ai) in entity_query.iter_mut() {
+        // Agents in a shortcut go straight through the level
+        if platformer_ai.shortcut_travel.is_some() {
+            continue;
+        }
+
         let mut adjustment = Vec2::ZERO;
         let mut new_normal = Vec2::ZERO;
 
-        for polygon_index in 0..level.polygons.len() {
-            let polygon = level.polygons.get(polygon_index).unwrap();
+        let position = transform.translation.xy();
 
-            let mut intersect_counter = 0;
-            let mut colliding_with_polygon = false;
+        let crawling = pathfinding.graphs[platformer_ai.profile].profile.can_crawl;
 
-            for line_index in 1..polygon.points.len() {
-                let start = polygon.points[line_index - 1];
-                let end = polygon.points[line_index];
+        // Only the lines the agent could be touching
+        let touch_radius = physics.radius + 0.5;
+        let nearby_lines = level.segment_index.query_aabb(
+            position - Vec2::splat(touch_radius),
+            position + Vec2::splat(touch_radius),
+        );
 
-                // Intersection detection
-                {
-                    let intersection = line_intersect(
-                        start,
-                        end,
-                        transform.translation.xy(),
-                        transform.translation.xy() + Vec2::new(2.0, 1.0) * 10000.0,
-                    );
+        let mut colliding_polygons: Vec<usize> = Vec::new();
 
-                    if intersection.is_some() {
-                        intersect_counter += 1;
-                    }
-                }
+        for polygon_line in nearby_lines {
+            let (start, end) = level.get_polygon_line(&polygon_line);
 
-                let previous_side_of_line =
-                    side_of_line_detection(start, end, physics.prev_position);
+            let previous_side_of_line = side_of_line_detection(start, end, physics.prev_position);
 
-                if previous_side_of_line != 1.0 {
-                    continue;
-                }
+            if previous_side_of_line != 1.0 {
+                continue;
+            }
 
-                let (distance_sq, projection) =
-                    find_projection(start, end, transform.translation.xy(), physics.radius);
-
-                let colliding_with_line = distance_sq <= physics.radius.powi(2);
-                colliding_with_polygon = colliding_with_polygon || colliding_with_line;
-
-                let touch_radius = physics.radius + 0.5;
-
-                let touching_line = distance_sq <= touch_radius.powi(2);
-
-                if touching_line {
-                    let normal_dir = (transform.translation.xy() - projection).normalize_or_zero();
-
-                    // If the line is not above the player
-                    if normal_dir.y >= -0.01 {
-                        // Add the normal dir to the players new normal
-                        new_normal -= normal_dir;
-
-                        // If the player is on a wall
-                        if normal_dir.x.abs() >= 0.8 {
-                            physics.walled = normal_dir.x.signum() as i8;
-                            physics.has_wall_jumped = false;
-                            physics.grounded = false;
-                            platformer_ai.jump_from_pos = None;
-                            platformer_ai.jump_to_pos = None;
-                        }
-                        // If the player is on the ground
-                        else if normal_dir.y > 0.01 {
-                            physics.grounded = true;
-                            physics.walled = 0;
-                            physics.has_wall_jumped = false;
-                            platformer_ai.jump_from_pos = None;
-                            platformer_ai.jump_to_pos = None;
-                        }
-                    }
-                }
+            let (distance_sq, projection) =
+                find_projection(start, end, transform.translation.xy(), physics.radius);
 
-                if colliding_with_line {
-                    let mut delta = (transform.translation.xy() - projection).normalize_or_zero();
+            let colliding_with_line = distance_sq <= physics.radius.powi(2);
 
-                    if delta.y < -0.01 {
-                        // println!("Hit ceiling");
-                        physics.velocity.y = 0.0;
-                    }
+            if colliding_with_line && !colliding_polygons.contains(&polygon_line.polygon_index) {
+                colliding_polygons.push(polygon_line.polygon_index);
+            }
+
+            let touching_line = distance_sq <= touch_radius.powi(2);
 
-                    delta *= physics.radius - distance_sq.sqrt();
+            if touching_line {
+                let normal_dir = (transform.translation.xy() - projection).normalize_or_zero();
 
-                    if delta.x.abs() > adjustment.x.abs() {
-                        adjustment.x = delta.x;
+                // If the line is not above the player, or the player can cling to it
+                if normal_dir.y >= -0.01 || crawling {
+                    // Add the normal dir to the players new normal
+                    new_normal -= normal_dir;
+
+                    // If the player is on a wall
+                    if normal_dir.x.abs() >= 0.8 {
+                        physics.walled = normal_dir.x.signum() as i8;
+                        physics.has_wall_jumped = false;
+                        physics.grounded = false;
+                        platformer_ai.jump_from_pos = None;
+                        platformer_ai.jump_to_pos = None;
                     }
-                    if delta.y.abs() > adjustment.y.abs() {
-                        adjustment.y = delta.y;
+                    // If the player is on the ground
+                    else if normal_dir.y > 0.01 {
+                        physics.grounded = true;
+                        physics.walled = 0;
+                        physics.has_wall_jumped = false;
+                        platformer_ai.jump_from_pos = None;
+                        platformer_ai.jump_to_pos = None;
                     }
                 }
             }
 
-            let inside_polygon = if polygon.is_container {
-                intersect_counter % 2 == 0
-            } else {
-                intersect_counter % 2 == 1
-            };
+            if colliding_with_line {
+                let mut delta = (transform.translation.xy() - projection).normalize_or_zero();
+
+                if delta.y < -0.01 && !crawling {
+                    // println!("Hit ceiling");
+                    physics.velocity.y = 0.0;
+                }
+
+                delta *= physics.radius - distance_sq.sqrt();
+
+                if delta.x.abs() > adjustment.x.abs() {
+                    adjustment.x = delta.x;
+                }
+                if delta.y.abs() > adjustment.y.abs() {
+                    adjustment.y = delta.y;
+                }
+            }
+        }
+
+        // If the agent ended up on the solid side of a polygon, put it back
+        for polygon_index in colliding_polygons {
+            let polygon = &level.polygons[polygon_index];
 
-            if colliding_with_polygon && inside_polygon {
+            let inside_polygon = level.point_in_polygon(polygon_index, position);
+            let inside_solid = inside_polygon != polygon.is_container;
+
+            if inside_solid {
                 println!("Clipped");
                 transform.translation = physics.prev_position.extend(0.0);
             }
         }
 
+        // Crawling agents hold on to a surface they just lost contact with,
+        // which is what lets them wrap around corners
+        if crawling && new_normal == Vec2::ZERO && platformer_ai.jump_from_pos.is_none() {
+            if let Some(grip_point) = find_grip_point(&level, &physics, position) {
+                new_normal = grip_point - position;
+            }
+        }
+
         // Update the players normal
         new_normal = new_normal.normalize_or_zero();
         physics.normal = new_normal;
@@ -145,6 +161,38 @@ pub fn s_collision(
     }
 }
 
+/// The closest point on a surface that a crawling agent is close enough to hold on to
+fn find_grip_point(level: &Level, physics: &Physics, position: Vec2) -> Option<Vec2> {
+    let grip_radius = physics.radius * CRAWL_GRIP_RADIUS_SCALE;
+
+    let nearby_lines = level.segment_index.query_aabb(
+        position - Vec2::splat(grip_radius),
+        position + Vec2::splat(grip_radius),
+    );
+
+    let mut grip_point = None;
+    let mut closest_distance_sq = grip_radius.powi(2);
+
+    for polygon_line in nearby_lines {
+        let (start, end) = level.get_polygon_line(&polygon_line);
+
+        if side_of_line_detection(start, end, physics.prev_position) != 1.0 {
+            continue;
+        }
+
+        let (_, projection) = find_projection(start, end, position, physics.radius);
+
+        let distance_sq = (projection - position).length_squared();
+
+        if distance_sq <= closest_distance_sq {
+            closest_distance_sq = distance_sq;
+            grip_point = Some(projection);
+        }
+    }
+
+    grip_point
+}
+
 pub fn find_projection(start: Vec2, end: Vec2, point: Vec2, radius: f32) -> (f32, Vec2) {
     let point_vec = point - start;
     let line_vec = end - start;
@@ -166,5 +214,5 @@ pub fn find_projection(start: Vec2, end: Vec2, point: Vec2, radius: f32) -> (f32
 
     let dist = (point - projection_point).length_squared();
 
-    return (dist, projection_point);
+    (dist, projection_point)
 }
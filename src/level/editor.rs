@@ -0,0 +1,300 @@
+use bevy::{
+    app::{App, Plugin, Update},
+    asset::Assets,
+    ecs::{
+        query::With,
+        schedule::{common_conditions::resource_exists, IntoSystemConfigs},
+        system::{Query, Res, ResMut, Resource},
+    },
+    gizmos::gizmos::Gizmos,
+    input::{keyboard::KeyCode, mouse::MouseButton, Input},
+    log::{error, info, warn},
+    math::Vec2,
+    render::color::Color,
+    window::{PrimaryWindow, Window},
+};
+
+use super::{
+    loader::{LevelData, LevelGeometry, LevelHandle, LevelPath},
+    tileset::Tileset,
+    Level,
+};
+
+/// Lets you paint tiles into the level with the mouse.
+/// While the mouse is held only the outline preview is rebuilt. The stroke goes into the level
+/// asset when the mouse is let go, so the level and the pathfinding graph are rebuilt once,
+/// the same way they are on a hot reload.
+pub struct LevelEditorPlugin;
+
+impl Plugin for LevelEditorPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(LevelEditor {
+            active: false,
+            selected_tile: 1,
+            stroke_grid: None,
+            stroke_preview: None,
+        })
+        .add_systems(Update, s_editor_input)
+        .add_systems(
+            Update,
+            s_render_editor
+                .after(s_editor_input)
+                .run_if(resource_exists::<Level>()),
+        );
+    }
+}
+
+#[derive(Resource)]
+pub struct LevelEditor {
+    pub active: bool,
+    pub selected_tile: usize,
+    /// The grid with the tiles painted since the mouse was pressed, until it's let go
+    pub stroke_grid: Option<Vec<Vec<usize>>>,
+    /// The level built from `stroke_grid`, which is only used to draw its outlines
+    pub stroke_preview: Option<Level>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn s_editor_input(
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    q_windows: Query<&Window, With<PrimaryWindow>>,
+    mut level_editor: ResMut<LevelEditor>,
+    level: Option<Res<Level>>,
+    level_handle: Option<Res<LevelHandle>>,
+    level_path: Res<LevelPath>,
+    mut level_data: ResMut<Assets<LevelData>>,
+    tilesets: Res<Assets<Tileset>>,
+) {
+    // E to toggle the editor
+    if keyboard_input.just_pressed(KeyCode::E) {
+        level_editor.active = !level_editor.active;
+        info!("Level editor active: {}", level_editor.active);
+    }
+
+    let (Some(level), Some(level_handle)) = (level, level_handle) else {
+        return;
+    };
+
+    let painting = level_editor.active
+        && (mouse_buttons.pressed(MouseButton::Left) || mouse_buttons.pressed(MouseButton::Right));
+
+    // The stroke is over, so put it into the level, which rebuilds it and its pathfinding graph
+    if !painting {
+        level_editor.stroke_preview = None;
+
+        if let Some(stroke_grid) = level_editor.stroke_grid.take() {
+            // Any mutable access counts as a modification, so only touch the asset if the
+            // stroke actually changed something
+            let stroke_changed =
+                level_data
+                    .get(&level_handle.handle)
+                    .is_some_and(|current_level_data| match &current_level_data.geometry {
+                        LevelGeometry::Grid(grid) => *grid != stroke_grid,
+                        LevelGeometry::Polygons(_) => false,
+                    });
+
+            if stroke_changed {
+                if let Some(LevelData {
+                    geometry: LevelGeometry::Grid(grid),
+                    ..
+                }) = level_data.get_mut(&level_handle.handle)
+                {
+                    *grid = stroke_grid;
+                }
+            }
+        }
+    }
+
+    if !level_editor.active {
+        return;
+    }
+
+    let Some(tileset) = tilesets.get(&level_handle.tileset_handle) else {
+        return;
+    };
+
+    // [ and ] to cycle through the tiles in the tileset
+    {
+        let mut tile_ids: Vec<usize> = tileset.tiles.keys().copied().collect();
+        tile_ids.sort();
+
+        let selected_index = tile_ids
+            .iter()
+            .position(|tile| *tile == level_editor.selected_tile)
+            .unwrap_or(0);
+
+        if keyboard_input.just_pressed(KeyCode::BracketRight) {
+            level_editor.selected_tile = tile_ids[(selected_index + 1) % tile_ids.len()];
+            info!("Selected tile: {}", level_editor.selected_tile);
+        }
+        if keyboard_input.just_pressed(KeyCode::BracketLeft) {
+            level_editor.selected_tile =
+                tile_ids[(selected_index + tile_ids.len() - 1) % tile_ids.len()];
+            info!("Selected tile: {}", level_editor.selected_tile);
+        }
+    }
+
+    // Ctrl + S to save the level back to its file
+    let control_pressed = keyboard_input.pressed(KeyCode::ControlLeft)
+        || keyboard_input.pressed(KeyCode::ControlRight);
+
+    if control_pressed && keyboard_input.just_pressed(KeyCode::S) {
+        let is_json_file = level_path
+            .file_path
+            .as_ref()
+            .is_some_and(|file_path| file_path.extension().is_some_and(|ext| ext == "json"));
+
+        let level_json = level_data
+            .get(&level_handle.handle)
+            .and_then(|current_level_data| current_level_data.to_json());
+
+        if let (true, Some(file_path), Some(level_json)) =
+            (is_json_file, &level_path.file_path, level_json)
+        {
+            match std::fs::write(file_path, level_json) {
+                Ok(()) => info!("Saved level to {}", file_path.display()),
+                Err(error) => error!("Could not save level to {}: {}", file_path.display(), error),
+            }
+        } else {
+            warn!("This level can't be saved, only tile grids loaded from .json files can be");
+        }
+    }
+
+    // Left click to paint the selected tile, right click to erase
+    let tile = if mouse_buttons.pressed(MouseButton::Left) {
+        level_editor.selected_tile
+    } else if mouse_buttons.pressed(MouseButton::Right) {
+        0
+    } else {
+        return;
+    };
+
+    let Some(mouse_pos_world) = get_mouse_pos_world(&q_windows) else {
+        return;
+    };
+
+    let Some((x, y)) = world_to_grid_cell(&level, mouse_pos_world) else {
+        return;
+    };
+
+    // Start the stroke from the level as it is, polygon levels don't have tiles to paint
+    if level_editor.stroke_grid.is_none() {
+        level_editor.stroke_grid =
+            level_data
+                .get(&level_handle.handle)
+                .and_then(|current_level_data| match &current_level_data.geometry {
+                    LevelGeometry::Grid(grid) => Some(grid.clone()),
+                    LevelGeometry::Polygons(_) => None,
+                });
+    }
+
+    let Some(stroke_grid) = &mut level_editor.stroke_grid else {
+        return;
+    };
+
+    if stroke_grid[y][x] == tile {
+        return;
+    }
+
+    stroke_grid[y][x] = tile;
+
+    // Only the outlines are rebuilt while painting, the pathfinding graph waits for the stroke to end
+    match Level::from_grid(stroke_grid, tileset, level.grid_size) {
+        Ok(stroke_preview) => level_editor.stroke_preview = Some(stroke_preview),
+        Err(error) => warn!("Could not preview the level: {}", error),
+    }
+}
+
+pub fn s_render_editor(
+    mut gizmos: Gizmos,
+    level: Res<Level>,
+    level_editor: Res<LevelEditor>,
+    level_handle: Option<Res<LevelHandle>>,
+    tilesets: Res<Assets<Tileset>>,
+    q_windows: Query<&Window, With<PrimaryWindow>>,
+) {
+    if !level_editor.active {
+        return;
+    }
+
+    // Draw the outlines of the stroke being painted over the level
+    if let Some(stroke_preview) = &level_editor.stroke_preview {
+        for polygon in &stroke_preview.polygons {
+            gizmos.linestrip_2d(polygon.points.to_vec(), Color::CYAN);
+        }
+    }
+
+    // Draw the grid bounds
+    gizmos.rect_2d(
+        Vec2::ZERO,
+        0.0,
+        level.size * level.grid_size,
+        Color::CYAN.with_a(0.3),
+    );
+
+    let Some(mouse_pos_world) = get_mouse_pos_world(&q_windows) else {
+        return;
+    };
+
+    let Some((x, y)) = world_to_grid_cell(&level, mouse_pos_world) else {
+        return;
+    };
+
+    let cell_top_left = level.grid_to_world(Vec2::new(x as f32, y as f32));
+
+    // Draw the hovered cell
+    gizmos.rect_2d(
+        cell_top_left + Vec2::new(level.grid_size, -level.grid_size) / 2.0,
+        0.0,
+        Vec2::splat(level.grid_size),
+        Color::CYAN.with_a(0.5),
+    );
+
+    // Draw the outline of the selected tile inside the hovered cell
+    let tile_definition = level_handle
+        .and_then(|level_handle| tilesets.get(&level_handle.tileset_handle))
+        .and_then(|tileset| tileset.tiles.get(&level_editor.selected_tile));
+
+    if let Some(tile_definition) = tile_definition {
+        let mut outline: Vec<Vec2> = tile_definition
+            .outline
+            .iter()
+            .map(|point| level.grid_to_world(Vec2::new(x as f32, y as f32) + *point))
+            .collect();
+        outline.push(outline[0]);
+
+        gizmos.linestrip_2d(outline, Color::CYAN);
+    }
+}
+
+fn get_mouse_pos_world(q_windows: &Query<&Window, With<PrimaryWindow>>) -> Option<Vec2> {
+    let window = q_windows.get_single().ok()?;
+    let position = window.cursor_position()?;
+
+    let mut mouse_pos_world = position
+        - Vec2::new(
+            window.resolution.width() / 2.0,
+            window.resolution.height() / 2.0,
+        );
+    mouse_pos_world.y *= -1.0;
+
+    Some(mouse_pos_world)
+}
+
+/// Which grid cell a world position is in, if it's inside the level
+fn world_to_grid_cell(level: &Level, position: Vec2) -> Option<(usize, usize)> {
+    let offset = Vec2::new(
+        level.size.x * -level.grid_size / 2.0,
+        level.size.y * level.grid_size / 2.0,
+    );
+
+    let x = ((position.x - offset.x) / level.grid_size).floor();
+    let y = ((offset.y - position.y) / level.grid_size).floor();
+
+    if x < 0.0 || y < 0.0 || x >= level.size.x || y >= level.size.y {
+        return None;
+    }
+
+    Some((x as usize, y as usize))
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+
+use bevy::math::Vec2;
+
+/// Everything that can go wrong while turning a level file into a [`super::Level`]
+#[derive(Debug)]
+pub enum LevelLoadError {
+    /// The level file could not be read
+    Io(std::io::Error),
+    /// The level file is not valid JSON, or not in one of the level formats
+    MalformedJson(serde_json::Error),
+    /// The grid has no rows, or its first row has no tiles
+    EmptyGrid,
+    /// A row has a different number of tiles than the first row
+    RaggedRow {
+        row: usize,
+        expected_length: usize,
+        length: usize,
+    },
+    /// A tile id that isn't in the tileset
+    UnknownTile { x: usize, y: usize, tile: usize },
+    /// A tileset entry whose outline isn't a shape inside its cell
+    InvalidTileDefinition { tile: usize },
+    /// A Tiled map without the layer the level is read from
+    MissingTiledLayer { name: &'static str },
+    /// A Tiled map that uses a feature the importer can't read
+    UnsupportedTiledMap { reason: &'static str },
+    /// A polygon in a polygon level with fewer than 3 points
+    InvalidPolygon { polygon: usize },
+    /// A polygon level without any polygons
+    NoPolygons,
+    /// A shortcut with fewer than 2 entrances, so it doesn't lead anywhere
+    InvalidShortcut { shortcut: usize },
+    /// An outline that stops before getting back to where it started.
+    /// `position` is where the outline stops, in grid cells.
+    UnclosedOutline { position: Vec2 },
+}
+
+impl fmt::Display for LevelLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LevelLoadError::Io(error) => write!(f, "could not read level file: {}", error),
+            LevelLoadError::MalformedJson(error) => {
+                write!(f, "could not parse level file: {}", error)
+            }
+            LevelLoadError::EmptyGrid => write!(f, "level grid is empty"),
+            LevelLoadError::RaggedRow {
+                row,
+                expected_length,
+                length,
+            } => write!(
+                f,
+                "row {} has {} tiles, but the first row has {}",
+                row, length, expected_length
+            ),
+            LevelLoadError::UnknownTile { x, y, tile } => {
+                write!(f, "unknown tile id {} at ({}, {})", tile, x, y)
+            }
+            LevelLoadError::InvalidTileDefinition { tile } => write!(
+                f,
+                "tile {} needs an outline of at least 3 points inside its cell",
+                tile
+            ),
+            LevelLoadError::MissingTiledLayer { name } => {
+                write!(f, "Tiled map has no tile layer called \"{}\"", name)
+            }
+            LevelLoadError::UnsupportedTiledMap { reason } => {
+                write!(f, "unsupported Tiled map, {}", reason)
+            }
+            LevelLoadError::InvalidPolygon { polygon } => {
+                write!(f, "polygon {} needs at least 3 points", polygon)
+            }
+            LevelLoadError::NoPolygons => write!(f, "level has no polygons"),
+            LevelLoadError::InvalidShortcut { shortcut } => {
+                write!(f, "shortcut {} needs at least 2 entrances", shortcut)
+            }
+            LevelLoadError::UnclosedOutline { position } => write!(
+                f,
+                "outline does not close, it stops at ({}, {})",
+                position.x, position.y
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LevelLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LevelLoadError::Io(error) => Some(error),
+            LevelLoadError::MalformedJson(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LevelLoadError {
+    fn from(error: std::io::Error) -> Self {
+        LevelLoadError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for LevelLoadError {
+    fn from(error: serde_json::Error) -> Self {
+        LevelLoadError::MalformedJson(error)
+    }
+}
@@ -0,0 +1,391 @@
+use std::{path::PathBuf, time::Duration};
+
+use bevy::{
+    app::{App, Plugin, Startup, Update},
+    asset::{
+        io::{AssetSource, AssetSourceId, Reader},
+        Asset, AssetApp, AssetEvent, AssetLoader, Assets, AsyncReadExt, Handle, LoadContext,
+    },
+    ecs::{
+        change_detection::DetectChanges,
+        event::EventReader,
+        system::{Commands, Query, Res, ResMut, Resource},
+    },
+    log::{error, info},
+    math::Vec2,
+    reflect::TypePath,
+    utils::BoxedFuture,
+};
+use serde::Deserialize;
+
+#[cfg(not(target_arch = "wasm32"))]
+use bevy::asset::{io::file::FileAssetReader, AssetServer};
+
+use crate::ai::{
+    d_star_lite::PathPlanner,
+    pathfinding::{init_pathfinding_graph, NodePlacementSettings, Pathfinding},
+    platformer_ai::PlatformerAI,
+};
+
+use super::{
+    error::LevelLoadError,
+    tiled::TiledMapLoader,
+    tileset::{Tileset, TilesetLoader},
+    Level,
+};
+
+/// The name of the asset source that levels passed with `--level` are read from
+const LEVEL_SOURCE_NAME: &str = "level";
+
+/// The level that is loaded when no `--level` argument is given
+const DEFAULT_LEVEL_PATH: &str = "level.json";
+
+/// The tileset that gives the tile ids in the level their shapes
+const TILESET_PATH: &str = "tiles.tileset.json";
+
+/// The level that is used on wasm, where there is no filesystem to read from
+#[cfg(target_arch = "wasm32")]
+const EMBEDDED_LEVEL_DATA: &[u8] = include_bytes!("../../assets/level.json");
+
+#[cfg(target_arch = "wasm32")]
+const EMBEDDED_TILESET_DATA: &[u8] = include_bytes!("../../assets/tiles.tileset.json");
+
+const GRID_SIZE: f32 = 32.0;
+
+/// How long to wait for a level file to stop changing before reloading it
+const LEVEL_FILE_DEBOUNCE_TIME: Duration = Duration::from_millis(300);
+
+/// Registers the asset source for the `--level` argument.
+/// This has to be added before `DefaultPlugins`.
+pub struct LevelSourcePlugin;
+
+impl Plugin for LevelSourcePlugin {
+    fn build(&self, app: &mut App) {
+        let level_file = level_file_from_args();
+
+        let (asset_path, file_path) = match level_file {
+            Some(level_file) => {
+                // Serve the directory the level is in, so any level on disk can be loaded
+                let level_directory = level_file
+                    .parent()
+                    .map(|parent| parent.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let level_file_name = level_file
+                    .file_name()
+                    .map(|file_name| file_name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+
+                app.register_asset_source(
+                    AssetSourceId::from(LEVEL_SOURCE_NAME),
+                    AssetSource::build()
+                        .with_reader(AssetSource::get_default_reader(level_directory.clone()))
+                        .with_watcher(AssetSource::get_default_watcher(
+                            level_directory,
+                            LEVEL_FILE_DEBOUNCE_TIME,
+                        )),
+                );
+
+                (
+                    format!("{}://{}", LEVEL_SOURCE_NAME, level_file_name),
+                    Some(level_file),
+                )
+            }
+            None => (DEFAULT_LEVEL_PATH.to_string(), default_level_file()),
+        };
+
+        app.insert_resource(LevelPath {
+            asset_path,
+            file_path,
+        });
+    }
+}
+
+/// Loads the level and builds the level geometry and pathfinding graph once it's ready.
+/// The level is rebuilt whenever its file changes on disk.
+pub struct LevelPlugin;
+
+impl Plugin for LevelPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<LevelData>()
+            .init_asset::<Tileset>()
+            .init_asset_loader::<LevelDataLoader>()
+            .init_asset_loader::<TilesetLoader>()
+            .init_asset_loader::<TiledMapLoader>()
+            .add_systems(Startup, s_load_level)
+            .add_systems(Update, s_build_level);
+    }
+}
+
+/// A level, as it's stored on disk
+#[derive(Asset, TypePath, Debug)]
+pub struct LevelData {
+    pub geometry: LevelGeometry,
+    /// In grid cells (y down) for grid levels, in world space for polygon levels
+    pub spawn_points: Vec<Vec2>,
+    pub goal_positions: Vec<Vec2>,
+    pub shortcuts: Vec<ShortcutData>,
+}
+
+#[derive(Debug)]
+pub enum LevelGeometry {
+    /// A grid of tile ids, shaped by the tileset
+    Grid(Vec<Vec<usize>>),
+    /// Polygons in world space, used as they are
+    Polygons(Vec<PolygonData>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PolygonData {
+    pub points: Vec<Vec2>,
+    /// Container polygons have the level inside of them, like the walls of a cave
+    #[serde(default)]
+    pub is_container: bool,
+}
+
+/// A shortcut pipe that agents can travel through to get between distant places, like in Rain World
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShortcutData {
+    /// The openings of the pipe, in order along it.
+    /// In grid cells (y down) for grid levels, in world space for polygon levels.
+    pub entrances: Vec<Vec2>,
+}
+
+/// The polygon level format
+#[derive(Deserialize)]
+struct PolygonLevelFile {
+    polygons: Vec<PolygonData>,
+    #[serde(default)]
+    spawn_points: Vec<Vec2>,
+    #[serde(default)]
+    goal_positions: Vec<Vec2>,
+    #[serde(default)]
+    shortcuts: Vec<ShortcutData>,
+}
+
+impl LevelData {
+    /// Reads either a grid level (an array of rows of tile ids)
+    /// or a polygon level (an object with a list of polygons)
+    pub fn from_bytes(bytes: &[u8]) -> Result<LevelData, LevelLoadError> {
+        let is_polygon_level = bytes
+            .iter()
+            .find(|byte| !byte.is_ascii_whitespace())
+            .is_some_and(|byte| *byte == b'{');
+
+        if is_polygon_level {
+            let polygon_level_file: PolygonLevelFile = serde_json::from_slice(bytes)?;
+
+            return Ok(LevelData {
+                geometry: LevelGeometry::Polygons(polygon_level_file.polygons),
+                spawn_points: polygon_level_file.spawn_points,
+                goal_positions: polygon_level_file.goal_positions,
+                shortcuts: polygon_level_file.shortcuts,
+            });
+        }
+
+        let grid: Vec<Vec<usize>> = serde_json::from_slice(bytes)?;
+
+        Ok(LevelData {
+            geometry: LevelGeometry::Grid(grid),
+            spawn_points: Vec::new(),
+            goal_positions: Vec::new(),
+            shortcuts: Vec::new(),
+        })
+    }
+
+    /// Writes a grid level in the same format it's read in, with one row per line.
+    /// Polygon levels can't be edited, so they are never written.
+    pub fn to_json(&self) -> Option<String> {
+        let LevelGeometry::Grid(grid) = &self.geometry else {
+            return None;
+        };
+
+        let rows: Vec<String> = grid
+            .iter()
+            .map(|row| {
+                let tiles: Vec<String> = row.iter().map(|tile| tile.to_string()).collect();
+                format!("  [{}]", tiles.join(", "))
+            })
+            .collect();
+
+        Some(format!("[\n{}\n]\n", rows.join(",\n")))
+    }
+}
+
+#[derive(Resource)]
+pub struct LevelPath {
+    pub asset_path: String,
+    /// Where the level file is on disk, if it's on disk at all
+    pub file_path: Option<PathBuf>,
+}
+
+#[derive(Resource)]
+pub struct LevelHandle {
+    pub handle: Handle<LevelData>,
+    pub tileset_handle: Handle<Tileset>,
+}
+
+#[derive(Default)]
+pub struct LevelDataLoader;
+
+impl AssetLoader for LevelDataLoader {
+    type Asset = LevelData;
+    type Settings = ();
+    type Error = LevelLoadError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<LevelData, LevelLoadError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+
+            LevelData::from_bytes(&bytes)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["json"]
+    }
+}
+
+/// Reads the level file from `--level <path>` or `--level=<path>`
+fn level_file_from_args() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        let level_file = if arg == "--level" {
+            args.next()
+        } else {
+            arg.strip_prefix("--level=").map(|path| path.to_string())
+        };
+
+        if let Some(level_file) = level_file {
+            let level_file = PathBuf::from(level_file);
+
+            // Make the path absolute, since asset sources are relative to the executable
+            return Some(std::fs::canonicalize(&level_file).unwrap_or(level_file));
+        }
+    }
+
+    None
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn default_level_file() -> Option<PathBuf> {
+    Some(
+        FileAssetReader::get_base_path()
+            .join("assets")
+            .join(DEFAULT_LEVEL_PATH),
+    )
+}
+
+#[cfg(target_arch = "wasm32")]
+fn default_level_file() -> Option<PathBuf> {
+    None
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn s_load_level(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    level_path: Res<LevelPath>,
+) {
+    let handle = asset_server.load(level_path.asset_path.clone());
+    let tileset_handle = asset_server.load(TILESET_PATH);
+
+    commands.insert_resource(LevelHandle {
+        handle,
+        tileset_handle,
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+fn s_load_level(
+    mut commands: Commands,
+    mut level_data: ResMut<Assets<LevelData>>,
+    mut tilesets: ResMut<Assets<Tileset>>,
+) {
+    let embedded_level_data = LevelData::from_bytes(EMBEDDED_LEVEL_DATA).unwrap();
+    let embedded_tileset = Tileset::from_bytes(EMBEDDED_TILESET_DATA).unwrap();
+
+    let handle = level_data.add(embedded_level_data);
+    let tileset_handle = tilesets.add(embedded_tileset);
+
+    commands.insert_resource(LevelHandle {
+        handle,
+        tileset_handle,
+    });
+}
+
+/// Builds the level once both the level and the tileset are loaded,
+/// and rebuilds it whenever either of them changes
+#[allow(clippy::too_many_arguments)]
+pub fn s_build_level(
+    mut commands: Commands,
+    mut level_data_events: EventReader<AssetEvent<LevelData>>,
+    mut tileset_events: EventReader<AssetEvent<Tileset>>,
+    level_data: Res<Assets<LevelData>>,
+    tilesets: Res<Assets<Tileset>>,
+    level_handle: Option<Res<LevelHandle>>,
+    level_path: Res<LevelPath>,
+    current_level: Option<Res<Level>>,
+    mut pathfinding: ResMut<Pathfinding>,
+    node_placement_settings: Res<NodePlacementSettings>,
+    mut platformer_ai_query: Query<&mut PlatformerAI>,
+) {
+    let Some(level_handle) = level_handle else {
+        return;
+    };
+
+    let level_data_changed = level_data_events.read().any(|event| {
+        event.is_added(&level_handle.handle) || event.is_modified(&level_handle.handle)
+    });
+    let tileset_changed = tileset_events.read().any(|event| {
+        event.is_added(&level_handle.tileset_handle)
+            || event.is_modified(&level_handle.tileset_handle)
+    });
+
+    if !level_data_changed && !tileset_changed && !node_placement_settings.is_changed() {
+        return;
+    }
+
+    let (Some(level_data), Some(tileset)) = (
+        level_data.get(&level_handle.handle),
+        tilesets.get(&level_handle.tileset_handle),
+    ) else {
+        return;
+    };
+
+    // Keep the current level if the new one is broken
+    let level = match Level::from_level_data(level_data, tileset, GRID_SIZE) {
+        Ok(level) => level,
+        Err(error) => {
+            error!("Could not build level {}: {}", level_path.asset_path, error);
+            return;
+        }
+    };
+
+    init_pathfinding_graph(&level, &mut pathfinding, &node_placement_settings);
+
+    if current_level.is_some() {
+        info!("Level reloaded");
+
+        // Forget anything that refers to the old pathfinding graph
+        for mut platformer_ai in platformer_ai_query.iter_mut() {
+            platformer_ai.current_target_node = None;
+            platformer_ai.jump_from_pos = None;
+            platformer_ai.jump_to_pos = None;
+            platformer_ai.shortcut_travel = None;
+            platformer_ai.path_planner = PathPlanner::default();
+        }
+    } else if let Some(goal_position) = level.goal_positions.first() {
+        // Put the goal point where the level wants it. The agents are spawned at its spawn point
+        // once the level exists.
+        pathfinding.goal_position = *goal_position;
+    }
+
+    commands.insert_resource(level);
+}
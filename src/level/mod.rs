@@ -1,23 +1,214 @@
+use std::collections::HashMap;
+
 use bevy::{ecs::system::Resource, math::Vec2, render::color::Color};
 use rand::Rng;
 
 use crate::utils::line_intersect;
 
+pub mod editor;
+pub mod error;
+pub mod loader;
+pub mod spatial_index;
+pub mod tiled;
+pub mod tileset;
+
+use error::LevelLoadError;
+use loader::{LevelData, LevelGeometry, PolygonData, ShortcutData};
+use spatial_index::SegmentIndex;
+use tileset::Tileset;
+
 pub struct Polygon {
     pub points: Vec<Vec2>,
     pub color: Color,
     pub is_container: bool,
 }
 
+impl Polygon {
+    pub fn area(&self) -> f32 {
+        calculate_winding_order(&self.points).abs() / 2.0
+    }
+}
+
+/// A shortcut pipe, in world space
+pub struct Shortcut {
+    /// The openings of the pipe, in order along it
+    pub entrances: Vec<Vec2>,
+}
+
+impl Shortcut {
+    /// The points the pipe goes through from one entrance to another, including both entrances
+    pub fn get_pipe(&self, from: usize, to: usize) -> Vec<Vec2> {
+        if from <= to {
+            self.entrances[from..=to].to_vec()
+        } else {
+            self.entrances[to..=from].iter().rev().copied().collect()
+        }
+    }
+}
+
 #[derive(Resource)]
 pub struct Level {
     pub polygons: Vec<Polygon>,
     pub grid_size: f32,
     pub size: Vec2,
     pub half_size: Vec2,
+    /// Where agents start, in world space
+    pub spawn_points: Vec<Vec2>,
+    /// Where the goal point can be placed, in world space
+    pub goal_positions: Vec<Vec2>,
+    pub shortcuts: Vec<Shortcut>,
+    /// Finds the lines near a point, box or segment
+    pub segment_index: SegmentIndex,
 }
 
 impl Level {
+    /// Builds the level from either of the level formats.
+    /// `grid_size` is the size of a tile, and the unit `size` is measured in.
+    pub fn from_level_data(
+        level_data: &LevelData,
+        tileset: &Tileset,
+        grid_size: f32,
+    ) -> Result<Level, LevelLoadError> {
+        match &level_data.geometry {
+            LevelGeometry::Grid(level_grid_data) => {
+                let mut level = Level::from_grid(level_grid_data, tileset, grid_size)?;
+
+                // Grid levels place things in grid cells
+                level.spawn_points = level_data
+                    .spawn_points
+                    .iter()
+                    .map(|spawn_point| level.grid_to_world(*spawn_point))
+                    .collect();
+                level.goal_positions = level_data
+                    .goal_positions
+                    .iter()
+                    .map(|goal_position| level.grid_to_world(*goal_position))
+                    .collect();
+                level.shortcuts = get_shortcuts(&level_data.shortcuts, |entrance| {
+                    level.grid_to_world(entrance)
+                })?;
+
+                Ok(level)
+            }
+            LevelGeometry::Polygons(polygon_data) => {
+                let mut level = Level::from_polygons(polygon_data, grid_size)?;
+
+                level.spawn_points = level_data.spawn_points.clone();
+                level.goal_positions = level_data.goal_positions.clone();
+                level.shortcuts = get_shortcuts(&level_data.shortcuts, |entrance| entrance)?;
+
+                Ok(level)
+            }
+        }
+    }
+
+    /// Builds the level geometry straight from a list of polygons in world space
+    pub fn from_polygons(
+        polygon_data: &[PolygonData],
+        grid_size: f32,
+    ) -> Result<Level, LevelLoadError> {
+        let mut rng = rand::thread_rng();
+
+        let container_color = random_color(&mut rng);
+
+        let mut polygons: Vec<Polygon> = Vec::new();
+
+        // The level is centered on the origin, so make it big enough to reach every point
+        let mut max_extent = Vec2::ZERO;
+
+        for (polygon_index, polygon) in polygon_data.iter().enumerate() {
+            let mut points = polygon.points.clone();
+
+            // Polygons are stored closed, with the first point repeated at the end
+            if points.len() > 1 && points.first() == points.last() {
+                points.pop();
+            }
+
+            if points.len() < 3 {
+                return Err(LevelLoadError::InvalidPolygon {
+                    polygon: polygon_index,
+                });
+            }
+
+            points.push(points[0]);
+
+            normalize_winding(&mut points, polygon.is_container);
+
+            for point in &points {
+                max_extent = max_extent.max(point.abs());
+            }
+
+            polygons.push(Polygon {
+                points,
+                color: if polygon.is_container {
+                    container_color
+                } else {
+                    random_color(&mut rng)
+                },
+                is_container: polygon.is_container,
+            });
+        }
+
+        if polygons.is_empty() {
+            return Err(LevelLoadError::NoPolygons);
+        }
+
+        let size = max_extent * 2.0 / grid_size;
+
+        Ok(Level {
+            segment_index: build_segment_index(
+                polygons.iter().map(|polygon| polygon.points.as_slice()),
+                grid_size,
+            ),
+            polygons,
+            grid_size,
+            size,
+            half_size: size / 2.0,
+            spawn_points: Vec::new(),
+            goal_positions: Vec::new(),
+            shortcuts: Vec::new(),
+        })
+    }
+
+    /// Builds the level geometry from a grid of tile ids, using the tileset for the tile shapes
+    pub fn from_grid(
+        level_grid_data: &[Vec<usize>],
+        tileset: &Tileset,
+        grid_size: f32,
+    ) -> Result<Level, LevelLoadError> {
+        validate_level_grid(level_grid_data, tileset)?;
+
+        let (polygons, size, half_size) =
+            generate_level_polygons(level_grid_data, tileset, grid_size)?;
+
+        Ok(Level {
+            segment_index: build_segment_index(
+                polygons.iter().map(|polygon| polygon.points.as_slice()),
+                grid_size,
+            ),
+            polygons,
+            grid_size,
+            size,
+            half_size,
+            spawn_points: Vec::new(),
+            goal_positions: Vec::new(),
+            shortcuts: Vec::new(),
+        })
+    }
+
+    /// Turns a position in grid cells (y down) into a world position
+    pub fn grid_to_world(&self, position: Vec2) -> Vec2 {
+        let offset = Vec2::new(
+            self.size.x * -self.grid_size / 2.0,
+            self.size.y * self.grid_size / 2.0,
+        );
+
+        Vec2::new(
+            position.x * self.grid_size + offset.x,
+            -position.y * self.grid_size + offset.y,
+        )
+    }
+
     pub fn get_polygon(&self, index: usize) -> Option<&Polygon> {
         self.polygons.get(index)
     }
@@ -31,228 +222,322 @@ impl Level {
         Some((start, end))
     }
 
-    pub fn line_of_sight_check(&self, start: Vec2, end: Vec2) -> bool {
-        for polygon in &self.polygons {
-            for i in 1..polygon.points.len() {
-                let start = polygon.points[i - 1];
-                let end = polygon.points[i];
+    /// Finds the first line a ray hits within `max_dist` of its origin.
+    /// `direction` doesn't have to be normalized.
+    pub fn raycast(&self, origin: Vec2, direction: Vec2, max_dist: f32) -> Option<RaycastHit> {
+        let direction = direction.normalize_or_zero();
 
-                let intersection = line_intersect(start, end, start, end);
-
-                if intersection.is_some() {
-                    return false;
-                }
-            }
+        if direction == Vec2::ZERO {
+            return None;
         }
 
-        return true;
-    }
-}
+        let ray_end = origin + direction * max_dist;
 
-const LEVEL_DATA: &'static [u8] = include_bytes!("../../assets/level.json");
+        let mut closest_hit: Option<RaycastHit> = None;
 
-pub fn generate_level_polygons(grid_size: f32) -> (Vec<Polygon>, Vec2, Vec2) {
-    let mut rng = rand::thread_rng();
+        for polygon_line in self.segment_index.query_segment(origin, ray_end) {
+            let (start, end) = self.get_polygon_line(&polygon_line);
 
-    let res = std::str::from_utf8(LEVEL_DATA);
-    let level_grid_data: Vec<Vec<usize>> = serde_json::from_str(&res.unwrap()).unwrap();
+            let Some(point) = line_intersect(origin, ray_end, start, end) else {
+                continue;
+            };
 
-    let size = Vec2::new(
-        level_grid_data[0].len() as f32,
-        level_grid_data.len() as f32,
-    );
+            let distance = (point - origin).length();
 
-    let offset = Vec2::new(size.x * -grid_size / 2.0, size.y * grid_size / 2.0);
+            if closest_hit
+                .as_ref()
+                .is_some_and(|closest_hit| closest_hit.distance <= distance)
+            {
+                continue;
+            }
 
-    let mut line_points = get_line_points(level_grid_data, grid_size, size);
+            let line = end - start;
 
-    let mut line_count = line_points.len() / 2;
+            closest_hit = Some(RaycastHit {
+                point,
+                normal: Vec2::new(-line.y, line.x).normalize_or_zero(),
+                distance,
+                polygon_line,
+            });
+        }
 
-    // Remove superfluous points
+        closest_hit
+    }
 
-    let mut point_removal_data = Some(((0, 0), (0, 0)));
+    /// Sweeps a circle from `start` to `end` and finds the first line it touches.
+    /// Lines the circle already overlaps at `start` only count if it moves further into them,
+    /// so a circle resting on a surface can move away from it.
+    pub fn circle_cast(&self, start: Vec2, end: Vec2, radius: f32) -> Option<CircleCastHit> {
+        self.circle_cast_ignoring(start, end, radius, &[])
+    }
 
-    // While there are points to remove
-    while point_removal_data.is_some() {
-        point_removal_data = None;
+    /// Like [`Level::circle_cast`], but passes through `ignored_lines`
+    pub fn circle_cast_ignoring(
+        &self,
+        start: Vec2,
+        end: Vec2,
+        radius: f32,
+        ignored_lines: &[PolygonLine],
+    ) -> Option<CircleCastHit> {
+        let motion = end - start;
+
+        if motion.length_squared() == 0.0 {
+            return None;
+        }
 
-        'outer: for i in 0..line_count {
-            for j in 0..line_count {
-                // If the lines are the same, skip
-                if i == j {
-                    continue;
-                }
+        let mut closest_hit: Option<CircleCastHit> = None;
 
-                // Check if either of the points are shared
-
-                let line_1_start = line_points[i * 2];
-                let line_1_end = line_points[i * 2 + 1];
-
-                let line_2_start = line_points[j * 2];
-                let line_2_end = line_points[j * 2 + 1];
-
-                let mut shared_point: Option<(usize, usize)> = None;
-                let mut unique_points: Option<(usize, usize)> = None;
-
-                if line_1_start == line_2_start {
-                    shared_point = Some((i * 2, j * 2));
-                    unique_points = Some((i * 2 + 1, j * 2 + 1));
-                } else if line_1_start == line_2_end {
-                    shared_point = Some((i * 2, j * 2 + 1));
-                    unique_points = Some((i * 2 + 1, j * 2));
-                } else if line_1_end == line_2_start {
-                    shared_point = Some((i * 2 + 1, j * 2));
-                    unique_points = Some((i * 2, j * 2 + 1));
-                } else if line_1_end == line_2_end {
-                    shared_point = Some((i * 2 + 1, j * 2 + 1));
-                    unique_points = Some((i * 2, j * 2));
-                }
+        // Everything the circle could touch along the way
+        let nearby_lines = self.segment_index.query_aabb(
+            start.min(end) - Vec2::splat(radius),
+            start.max(end) + Vec2::splat(radius),
+        );
 
-                // If there is no shared point, skip
-                if shared_point.is_none() {
-                    continue;
-                }
+        for polygon_line in nearby_lines {
+            if ignored_lines.contains(&polygon_line) {
+                continue;
+            }
 
-                // Check if the lines are parallel
+            let (line_start, line_end) = self.get_polygon_line(&polygon_line);
 
-                let dot = (line_1_start - line_1_end)
-                    .normalize()
-                    .dot((line_2_start - line_2_end).normalize());
-                if dot.abs() == 1.0 {
-                    // if so flag the point for removal and break out of the outer for loop
-                    point_removal_data = Some((shared_point.unwrap(), unique_points.unwrap()));
-                    break 'outer;
-                }
-            }
-        }
+            let Some((time_of_impact, point)) =
+                circle_cast_line(start, motion, radius, line_start, line_end)
+            else {
+                continue;
+            };
 
-        // If there is a point to remove
-        if point_removal_data.is_some() {
-            let point_removal_data = point_removal_data.unwrap();
-
-            // Store the unique vertices
-            let unique_vert_1 = line_points[point_removal_data.1 .0].clone();
-            let unique_vert_2 = line_points[point_removal_data.1 .1].clone();
-
-            // Remove the shared and unique vertices
-            let mut removal_indices = vec![
-                point_removal_data.0 .0,
-                point_removal_data.0 .1,
-                point_removal_data.1 .0,
-                point_removal_data.1 .1,
-            ];
-            removal_indices.sort();
-            removal_indices.reverse();
-            for i in removal_indices {
-                line_points.remove(i);
+            if closest_hit
+                .as_ref()
+                .is_some_and(|closest_hit| closest_hit.time_of_impact <= time_of_impact)
+            {
+                continue;
             }
 
-            // Add the unique vertices back
-            line_points.push(unique_vert_1);
-            line_points.push(unique_vert_2);
+            let center = start + motion * time_of_impact;
 
-            // Update the line count
-            line_count -= 1;
+            closest_hit = Some(CircleCastHit {
+                time_of_impact,
+                point,
+                normal: (center - point).normalize_or_zero(),
+                polygon_line,
+            });
         }
+
+        closest_hit
     }
 
-    for i in 0..line_points.len() {
-        line_points[i].x += offset.x;
-        line_points[i].y *= -1.0;
-        line_points[i].y += offset.y;
+    /// Whether a point is inside of a polygon, ignoring which side of it is solid
+    pub fn point_in_polygon(&self, polygon_index: usize, point: Vec2) -> bool {
+        point_in_polygon(
+            &self.segment_index,
+            &self.polygons[polygon_index].points,
+            polygon_index,
+            point,
+        )
     }
 
-    // Separate the lines into polygons
-    let mut polygons: Vec<Polygon> = Vec::new();
+    /// The end points of a line
+    pub fn get_polygon_line(&self, polygon_line: &PolygonLine) -> (Vec2, Vec2) {
+        let points = &self.polygons[polygon_line.polygon_index].points;
 
-    let container_color = Color::rgb(
-        rng.gen_range(0.0..=1.0),
-        rng.gen_range(0.0..=1.0),
-        rng.gen_range(0.0..=1.0),
-    );
+        (
+            points[polygon_line.line_index],
+            points[polygon_line.line_index + 1],
+        )
+    }
 
-    // While there are lines left
-    while line_count > 0 {
-        // Create a new polygon
-        let mut polygon_lines: Vec<Vec2> = Vec::new();
+    /// Whether nothing in the level is between `start` and `end`
+    pub fn line_of_sight_check(&self, start: Vec2, end: Vec2) -> bool {
+        let delta = end - start;
 
-        // Add the first line to the polygon
-        polygon_lines.push(line_points[0].clone());
-        polygon_lines.push(line_points[1].clone());
+        self.raycast(start, delta, delta.length()).is_none()
+    }
+}
 
-        // Remove the first line from the list of lines
-        line_points.remove(0);
-        line_points.remove(0);
+#[derive(Debug, Clone)]
+pub struct CircleCastHit {
+    /// How far along the sweep the circle is when it touches the line,
+    /// from 0 at the start to 1 at the end
+    pub time_of_impact: f32,
+    /// Where the circle touches the line
+    pub point: Vec2,
+    /// Points from the contact point to the center of the circle
+    pub normal: Vec2,
+    pub polygon_line: PolygonLine,
+}
 
-        // Decrement the line count
-        line_count -= 1;
+/// When a circle moving by `motion` first touches a line,
+/// either on its flat side or on one of its end points
+fn circle_cast_line(
+    start: Vec2,
+    motion: Vec2,
+    radius: f32,
+    line_start: Vec2,
+    line_end: Vec2,
+) -> Option<(f32, Vec2)> {
+    let line = line_end - line_start;
+    let line_length = line.length();
+
+    let mut first_hit: Option<(f32, Vec2)> = None;
+
+    // The flat side of the line
+    if line_length > 0.0 {
+        let line_dir = line / line_length;
+        let line_normal = Vec2::new(-line_dir.y, line_dir.x);
+
+        // Which side of the line the circle starts on, and how far from it
+        let start_distance = line_normal.dot(start - line_start);
+        let side = if start_distance < 0.0 { -1.0 } else { 1.0 };
+        let approach_speed = -side * line_normal.dot(motion);
+
+        if approach_speed > 0.0 {
+            let time_of_impact = ((start_distance.abs() - radius) / approach_speed).max(0.0);
+
+            if time_of_impact <= 1.0 {
+                let center = start + motion * time_of_impact;
+                let point = center - line_normal * side * radius.min(start_distance.abs());
+                let along_line = line_dir.dot(point - line_start);
+
+                if (0.0..=line_length).contains(&along_line) {
+                    first_hit = Some((time_of_impact, point));
+                }
+            }
+        }
+    }
 
-        let start_vert = polygon_lines[0].clone();
-        let mut current_vert = polygon_lines[polygon_lines.len() - 1].clone();
+    // The end points, which are the corners of the polygon
+    for corner in [line_start, line_end] {
+        let to_start = start - corner;
 
-        // While the polygon is not closed
-        while start_vert != current_vert {
-            for i in 0..line_count {
-                let line_start = line_points[i * 2].clone();
-                let line_end = line_points[i * 2 + 1].clone();
+        // Only moving toward the corner can hit it
+        if motion.dot(to_start) >= 0.0 {
+            continue;
+        }
 
-                // If the line starts at the current vertex
-                if line_start == current_vert {
-                    // Add the line to the polygon
-                    polygon_lines.push(line_end.clone());
+        let a = motion.dot(motion);
+        let b = 2.0 * motion.dot(to_start);
+        let c = to_start.dot(to_start) - radius * radius;
 
-                    // Remove the line from the list of lines
-                    line_points.remove(i * 2);
-                    line_points.remove(i * 2);
+        let time_of_impact = if c <= 0.0 {
+            // Already overlapping the corner
+            0.0
+        } else {
+            let discriminant = b * b - 4.0 * a * c;
 
-                    // Decrement the line count
-                    line_count -= 1;
+            if discriminant < 0.0 {
+                continue;
+            }
 
-                    // Set the current vertex to the end of the line
-                    current_vert = line_end;
+            (-b - discriminant.sqrt()) / (2.0 * a)
+        };
 
-                    // Break out of the for loop
-                    break;
-                }
-                // If the line ends at the current vertex
-                else if line_end == current_vert {
-                    // Add the line to the polygon
-                    polygon_lines.push(line_start.clone());
+        if time_of_impact <= 1.0
+            && first_hit
+                .is_none_or(|(first_time_of_impact, _)| time_of_impact < first_time_of_impact)
+        {
+            first_hit = Some((time_of_impact, corner));
+        }
+    }
 
-                    // Remove the line from the list of lines
-                    line_points.remove(i * 2);
-                    line_points.remove(i * 2);
+    first_hit
+}
 
-                    // Decrement the line count
-                    line_count -= 1;
+#[derive(Debug, Clone)]
+pub struct RaycastHit {
+    pub point: Vec2,
+    /// The normal of the line that was hit, pointing out of the solid side
+    pub normal: Vec2,
+    /// How far along the ray the hit is
+    pub distance: f32,
+    pub polygon_line: PolygonLine,
+}
 
-                    // Set the current vertex to the start of the line
-                    current_vert = line_start;
+/// Makes sure the grid is rectangular and only contains known tiles
+fn validate_level_grid(
+    level_grid_data: &[Vec<usize>],
+    tileset: &Tileset,
+) -> Result<(), LevelLoadError> {
+    let row_length = level_grid_data.first().map(|row| row.len()).unwrap_or(0);
 
-                    // Break out of the for loop
-                    break;
-                }
-            }
+    if row_length == 0 {
+        return Err(LevelLoadError::EmptyGrid);
+    }
+
+    for (y, row) in level_grid_data.iter().enumerate() {
+        if row.len() != row_length {
+            return Err(LevelLoadError::RaggedRow {
+                row: y,
+                expected_length: row_length,
+                length: row.len(),
+            });
         }
 
-        let is_container = point_in_polygon(&polygon_lines, Vec2::new(0.0, 0.0));
-        let mut collision_side = calculate_winding_order(&polygon_lines).signum();
-        if is_container {
-            collision_side *= -1.0;
+        for (x, tile) in row.iter().enumerate() {
+            if !tileset.contains(*tile) {
+                return Err(LevelLoadError::UnknownTile { x, y, tile: *tile });
+            }
         }
+    }
+
+    Ok(())
+}
 
-        if collision_side == -1.0 {
-            polygon_lines.reverse();
+fn generate_level_polygons(
+    level_grid_data: &[Vec<usize>],
+    tileset: &Tileset,
+    grid_size: f32,
+) -> Result<(Vec<Polygon>, Vec2, Vec2), LevelLoadError> {
+    let mut rng = rand::thread_rng();
+
+    let size = Vec2::new(
+        level_grid_data[0].len() as f32,
+        level_grid_data.len() as f32,
+    );
+
+    let offset = Vec2::new(size.x * -grid_size / 2.0, size.y * grid_size / 2.0);
+
+    let line_points = get_line_points(level_grid_data, tileset, grid_size, size);
+
+    let mut outlines =
+        trace_outlines(&line_points).map_err(|position| LevelLoadError::UnclosedOutline {
+            position: position / grid_size,
+        })?;
+
+    // Separate the lines into polygons
+    let mut polygons: Vec<Polygon> = Vec::new();
+
+    let container_color = random_color(&mut rng);
+
+    for polygon_lines in outlines.iter_mut() {
+        for point in polygon_lines.iter_mut() {
+            point.x += offset.x;
+            point.y *= -1.0;
+            point.y += offset.y;
         }
+    }
+
+    // Containers are the outlines around the middle of the level
+    let outline_segment_index = build_segment_index(outlines.iter().map(Vec::as_slice), grid_size);
+    let outline_is_container: Vec<bool> = outlines
+        .iter()
+        .enumerate()
+        .map(|(outline_index, polygon_lines)| {
+            point_in_polygon(
+                &outline_segment_index,
+                polygon_lines,
+                outline_index,
+                Vec2::new(0.0, 0.0),
+            )
+        })
+        .collect();
+
+    for (mut polygon_lines, is_container) in outlines.into_iter().zip(outline_is_container) {
+        normalize_winding(&mut polygon_lines, is_container);
 
         let color = if is_container {
             container_color
         } else {
-            Color::rgb(
-                rng.gen_range(0.0..=1.0),
-                rng.gen_range(0.0..=1.0),
-                rng.gen_range(0.0..=1.0),
-            )
+            random_color(&mut rng)
         };
 
         // Add the polygon to the list of polygons
@@ -263,10 +548,170 @@ pub fn generate_level_polygons(grid_size: f32) -> (Vec<Polygon>, Vec2, Vec2) {
         });
     }
 
-    return (polygons, size, size / 2.0);
+    Ok((polygons, size, size / 2.0))
+}
+
+/// Identifies a vertex by its exact position, so lines that share an end point can be found
+type VertexKey = (u32, u32);
+
+fn get_vertex_key(point: Vec2) -> VertexKey {
+    // Adding 0.0 turns -0.0 into 0.0, so both end up with the same key
+    ((point.x + 0.0).to_bits(), (point.y + 0.0).to_bits())
 }
 
-fn calculate_winding_order(vertices: &Vec<Vec2>) -> f32 {
+/// Joins loose lines (pairs of points in `line_points`) into closed outlines.
+/// Each outline is closed, with its first point repeated at the end.
+/// Points in the middle of a straight run of lines are left out.
+///
+/// Returns where an outline stops if one of them can't be closed.
+fn trace_outlines(line_points: &[Vec2]) -> Result<Vec<Vec<Vec2>>, Vec2> {
+    let line_count = line_points.len() / 2;
+
+    // Every vertex, and the lines that touch it
+    let mut vertex_lines: HashMap<VertexKey, Vec<usize>> = HashMap::new();
+
+    for line_index in 0..line_count {
+        for point in &line_points[line_index * 2..line_index * 2 + 2] {
+            vertex_lines
+                .entry(get_vertex_key(*point))
+                .or_default()
+                .push(line_index);
+        }
+    }
+
+    let mut line_used = vec![false; line_count];
+
+    let mut outlines: Vec<Vec<Vec2>> = Vec::new();
+
+    for first_line_index in 0..line_count {
+        if line_used[first_line_index] {
+            continue;
+        }
+
+        line_used[first_line_index] = true;
+
+        let start_vert = line_points[first_line_index * 2];
+        let mut current_vert = line_points[first_line_index * 2 + 1];
+
+        let mut outline = vec![start_vert, current_vert];
+
+        // Follow the lines until we get back to where we started
+        while get_vertex_key(current_vert) != get_vertex_key(start_vert) {
+            let next_line_index = vertex_lines
+                .get(&get_vertex_key(current_vert))
+                .and_then(|lines| lines.iter().find(|line_index| !line_used[**line_index]))
+                .copied()
+                .ok_or(current_vert)?;
+
+            line_used[next_line_index] = true;
+
+            let line_start = line_points[next_line_index * 2];
+            let line_end = line_points[next_line_index * 2 + 1];
+
+            current_vert = if get_vertex_key(line_start) == get_vertex_key(current_vert) {
+                line_end
+            } else {
+                line_start
+            };
+
+            outline.push(current_vert);
+        }
+
+        outlines.push(remove_straight_points(outline, &vertex_lines));
+    }
+
+    Ok(outlines)
+}
+
+/// Removes points that are only there because two lines of the same straight edge meet.
+/// Points where more than two lines meet are kept, since other outlines touch them.
+fn remove_straight_points(
+    mut outline: Vec<Vec2>,
+    vertex_lines: &HashMap<VertexKey, Vec<usize>>,
+) -> Vec<Vec2> {
+    // Work on the open outline, so the start point can be removed like any other
+    outline.pop();
+
+    let point_count = outline.len();
+
+    let is_straight = |point_index: usize| {
+        let previous = outline[(point_index + point_count - 1) % point_count];
+        let point = outline[point_index];
+        let next = outline[(point_index + 1) % point_count];
+
+        let only_two_lines = vertex_lines
+            .get(&get_vertex_key(point))
+            .is_some_and(|lines| lines.len() == 2);
+
+        let direction_in = (point - previous).normalize();
+        let direction_out = (next - point).normalize();
+
+        // Exactly parallel, which is the same test the levels have always been built with,
+        // so existing levels keep exactly the same outlines
+        only_two_lines && direction_in.dot(direction_out) == 1.0
+    };
+
+    let mut simplified_outline: Vec<Vec2> = (0..point_count)
+        .filter(|point_index| !is_straight(*point_index))
+        .map(|point_index| outline[point_index])
+        .collect();
+
+    // Close the outline again
+    if let Some(first_point) = simplified_outline.first() {
+        simplified_outline.push(*first_point);
+    }
+
+    simplified_outline
+}
+
+/// Makes every polygon wind the same way relative to its solid side,
+/// so the line normals always point out of the solid
+fn normalize_winding(points: &mut [Vec2], is_container: bool) {
+    let mut collision_side = calculate_winding_order(points).signum();
+    if is_container {
+        collision_side *= -1.0;
+    }
+
+    if collision_side == -1.0 {
+        points.reverse();
+    }
+}
+
+/// Checks that every shortcut leads somewhere, and puts their entrances in world space
+fn get_shortcuts(
+    shortcut_data: &[ShortcutData],
+    to_world: impl Fn(Vec2) -> Vec2,
+) -> Result<Vec<Shortcut>, LevelLoadError> {
+    shortcut_data
+        .iter()
+        .enumerate()
+        .map(|(shortcut_index, shortcut)| {
+            if shortcut.entrances.len() < 2 {
+                return Err(LevelLoadError::InvalidShortcut {
+                    shortcut: shortcut_index,
+                });
+            }
+
+            Ok(Shortcut {
+                entrances: shortcut
+                    .entrances
+                    .iter()
+                    .map(|entrance| to_world(*entrance))
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+fn random_color(rng: &mut impl Rng) -> Color {
+    Color::rgb(
+        rng.gen_range(0.0..=1.0),
+        rng.gen_range(0.0..=1.0),
+        rng.gen_range(0.0..=1.0),
+    )
+}
+
+fn calculate_winding_order(vertices: &[Vec2]) -> f32 {
     let mut sum = 0.0;
 
     for i in 0..vertices.len() {
@@ -278,15 +723,26 @@ fn calculate_winding_order(vertices: &Vec<Vec2>) -> f32 {
     sum
 }
 
-fn point_in_polygon(polygon_lines: &Vec<Vec2>, point: Vec2) -> bool {
+/// Counts how many lines of the polygon a ray from the point crosses on its way out of the level
+fn point_in_polygon(
+    segment_index: &SegmentIndex,
+    polygon_lines: &[Vec2],
+    polygon_index: usize,
+    point: Vec2,
+) -> bool {
     let test_line_start = point;
-    let test_line_end = point + Vec2::new(2.0, 1.0) * 1000.0;
+    let test_line_end =
+        point + Vec2::new(2.0, 1.0).normalize() * segment_index.get_diagonal_length();
 
     let mut intersect_counter = 0;
 
-    for i in 1..polygon_lines.len() {
-        let start = polygon_lines[i - 1];
-        let end = polygon_lines[i];
+    for polygon_line in segment_index.query_segment(test_line_start, test_line_end) {
+        if polygon_line.polygon_index != polygon_index {
+            continue;
+        }
+
+        let start = polygon_lines[polygon_line.line_index];
+        let end = polygon_lines[polygon_line.line_index + 1];
 
         let intersection = line_intersect(start, end, test_line_start, test_line_end);
 
@@ -295,417 +751,526 @@ fn point_in_polygon(polygon_lines: &Vec<Vec2>, point: Vec2) -> bool {
         }
     }
 
-    return intersect_counter % 2 == 1;
+    intersect_counter % 2 == 1
+}
+
+/// The index cells are two tiles wide, so most queries only look at a few cells
+fn build_segment_index<'a>(
+    polygons: impl IntoIterator<Item = &'a [Vec2]>,
+    grid_size: f32,
+) -> SegmentIndex {
+    SegmentIndex::new(polygons, grid_size * 2.0)
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PolygonLine {
     pub polygon_index: usize,
     pub line_index: usize,
 }
 
-fn get_line_points(level_grid_data: Vec<Vec<usize>>, grid_cell_size: f32, size: Vec2) -> Vec<Vec2> {
+fn get_line_points(
+    level_grid_data: &[Vec<usize>],
+    tileset: &Tileset,
+    grid_cell_size: f32,
+    size: Vec2,
+) -> Vec<Vec2> {
     let mut line_points: Vec<Vec2> = Vec::new();
 
     for y in 0..size.y as usize {
         for x in 0..size.x as usize {
-            let tile = level_grid_data[y][x];
-
-            match tile {
-                1 => {
-                    // Squares
-
-                    // Left edge
-                    if x == 0 || level_grid_data[y][x - 1] == 0 {
-                        line_points.push(Vec2::new(
-                            x as f32 * grid_cell_size,
-                            y as f32 * grid_cell_size,
-                        ));
-                        line_points.push(Vec2::new(
-                            x as f32 * grid_cell_size,
-                            (y + 1) as f32 * grid_cell_size,
-                        ));
-                    }
-                    // Right edge
-                    if x == level_grid_data[y].len() - 1 || level_grid_data[y][x + 1] == 0 {
-                        line_points.push(Vec2::new(
-                            (x + 1) as f32 * grid_cell_size,
-                            y as f32 * grid_cell_size,
-                        ));
-                        line_points.push(Vec2::new(
-                            (x + 1) as f32 * grid_cell_size,
-                            (y + 1) as f32 * grid_cell_size,
-                        ));
-                    }
-                    // Top edge
-                    if y == 0 || level_grid_data[y - 1][x] == 0 {
-                        line_points.push(Vec2::new(
-                            x as f32 * grid_cell_size,
-                            y as f32 * grid_cell_size,
-                        ));
-                        line_points.push(Vec2::new(
-                            (x + 1) as f32 * grid_cell_size,
-                            y as f32 * grid_cell_size,
-                        ));
+            tileset.push_tile_edges(level_grid_data, x, y, &mut line_points);
+        }
+    }
+
+    for point in line_points.iter_mut() {
+        *point *= grid_cell_size;
+    }
+
+    line_points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// The original outline tracing, which merges lines one pair at a time.
+    /// It's slow, but it's what the levels were made with, so the new tracing is checked against it.
+    fn trace_outlines_reference(line_points: &[Vec2]) -> Vec<Vec<Vec2>> {
+        let mut line_points = line_points.to_vec();
+        let mut line_count = line_points.len() / 2;
+
+        // Remove superfluous points
+        let mut point_removal_data = Some(((0, 0), (0, 0)));
+
+        while point_removal_data.is_some() {
+            point_removal_data = None;
+
+            'outer: for i in 0..line_count {
+                for j in 0..line_count {
+                    if i == j {
+                        continue;
                     }
-                    // Bottom edge
-                    if y == size.y as usize - 1 || level_grid_data[y + 1][x] == 0 {
-                        line_points.push(Vec2::new(
-                            x as f32 * grid_cell_size,
-                            (y + 1) as f32 * grid_cell_size,
-                        ));
-                        line_points.push(Vec2::new(
-                            (x + 1) as f32 * grid_cell_size,
-                            (y + 1) as f32 * grid_cell_size,
-                        ));
+
+                    let line_1_start = line_points[i * 2];
+                    let line_1_end = line_points[i * 2 + 1];
+
+                    let line_2_start = line_points[j * 2];
+                    let line_2_end = line_points[j * 2 + 1];
+
+                    let (shared_point, unique_points) = if line_1_start == line_2_start {
+                        ((i * 2, j * 2), (i * 2 + 1, j * 2 + 1))
+                    } else if line_1_start == line_2_end {
+                        ((i * 2, j * 2 + 1), (i * 2 + 1, j * 2))
+                    } else if line_1_end == line_2_start {
+                        ((i * 2 + 1, j * 2), (i * 2, j * 2 + 1))
+                    } else if line_1_end == line_2_end {
+                        ((i * 2 + 1, j * 2 + 1), (i * 2, j * 2))
+                    } else {
+                        continue;
+                    };
+
+                    let dot = (line_1_start - line_1_end)
+                        .normalize()
+                        .dot((line_2_start - line_2_end).normalize());
+                    if dot.abs() == 1.0 {
+                        point_removal_data = Some((shared_point, unique_points));
+                        break 'outer;
                     }
                 }
-                2..=5 => {
-                    // Right triangles
-
-                    let triangle_type = tile - 2;
-
-                    match triangle_type {
-                        0 => {
-                            // Bottom left
-
-                            // Hypotenuse
-                            line_points.push(Vec2::new(
-                                x as f32 * grid_cell_size,
-                                y as f32 * grid_cell_size,
-                            ));
-                            line_points.push(Vec2::new(
-                                (x + 1) as f32 * grid_cell_size,
-                                (y + 1) as f32 * grid_cell_size,
-                            ));
-
-                            // Bottom edge
-                            if y == size.y as usize - 1 || level_grid_data[y + 1][x] == 0 {
-                                line_points.push(Vec2::new(
-                                    x as f32 * grid_cell_size,
-                                    (y + 1) as f32 * grid_cell_size,
-                                ));
-                                line_points.push(Vec2::new(
-                                    (x + 1) as f32 * grid_cell_size,
-                                    (y + 1) as f32 * grid_cell_size,
-                                ));
-                            }
-
-                            // Left edge
-                            if x == 0 || level_grid_data[y][x - 1] == 0 {
-                                line_points.push(Vec2::new(
-                                    x as f32 * grid_cell_size,
-                                    y as f32 * grid_cell_size,
-                                ));
-                                line_points.push(Vec2::new(
-                                    x as f32 * grid_cell_size,
-                                    (y + 1) as f32 * grid_cell_size,
-                                ));
-                            }
-                        }
-                        1 => {
-                            // Bottom right
-
-                            // Hypotenuse
-                            line_points.push(Vec2::new(
-                                (x + 1) as f32 * grid_cell_size,
-                                y as f32 * grid_cell_size,
-                            ));
-                            line_points.push(Vec2::new(
-                                x as f32 * grid_cell_size,
-                                (y + 1) as f32 * grid_cell_size,
-                            ));
-
-                            // Bottom edge
-                            if y == size.y as usize - 1 || level_grid_data[y + 1][x] == 0 {
-                                line_points.push(Vec2::new(
-                                    x as f32 * grid_cell_size,
-                                    (y + 1) as f32 * grid_cell_size,
-                                ));
-                                line_points.push(Vec2::new(
-                                    (x + 1) as f32 * grid_cell_size,
-                                    (y + 1) as f32 * grid_cell_size,
-                                ));
-                            }
-
-                            // Right edge
-                            if x == level_grid_data[y].len() - 1 || level_grid_data[y][x + 1] == 0 {
-                                line_points.push(Vec2::new(
-                                    (x + 1) as f32 * grid_cell_size,
-                                    y as f32 * grid_cell_size,
-                                ));
-                                line_points.push(Vec2::new(
-                                    (x + 1) as f32 * grid_cell_size,
-                                    (y + 1) as f32 * grid_cell_size,
-                                ));
-                            }
-                        }
-                        2 => {
-                            // Top left
-
-                            // Hypotenuse
-                            line_points.push(Vec2::new(
-                                x as f32 * grid_cell_size,
-                                (y + 1) as f32 * grid_cell_size,
-                            ));
-                            line_points.push(Vec2::new(
-                                (x + 1) as f32 * grid_cell_size,
-                                y as f32 * grid_cell_size,
-                            ));
-
-                            // Top edge
-                            if y == 0 || level_grid_data[y - 1][x] == 0 {
-                                line_points.push(Vec2::new(
-                                    x as f32 * grid_cell_size,
-                                    y as f32 * grid_cell_size,
-                                ));
-                                line_points.push(Vec2::new(
-                                    (x + 1) as f32 * grid_cell_size,
-                                    y as f32 * grid_cell_size,
-                                ));
-                            }
-
-                            // Left edge
-                            if x == 0 || level_grid_data[y][x - 1] == 0 {
-                                line_points.push(Vec2::new(
-                                    x as f32 * grid_cell_size,
-                                    y as f32 * grid_cell_size,
-                                ));
-                                line_points.push(Vec2::new(
-                                    x as f32 * grid_cell_size,
-                                    (y + 1) as f32 * grid_cell_size,
-                                ));
-                            }
-                        }
-                        3 => {
-                            // Top right
-
-                            // Hypotenuse
-                            line_points.push(Vec2::new(
-                                (x + 1) as f32 * grid_cell_size,
-                                (y + 1) as f32 * grid_cell_size,
-                            ));
-                            line_points.push(Vec2::new(
-                                x as f32 * grid_cell_size,
-                                y as f32 * grid_cell_size,
-                            ));
-
-                            // Top edge
-                            if y == 0 || level_grid_data[y - 1][x] == 0 {
-                                line_points.push(Vec2::new(
-                                    x as f32 * grid_cell_size,
-                                    y as f32 * grid_cell_size,
-                                ));
-                                line_points.push(Vec2::new(
-                                    (x + 1) as f32 * grid_cell_size,
-                                    y as f32 * grid_cell_size,
-                                ));
-                            }
-
-                            // Right edge
-                            if x == level_grid_data[y].len() - 1 || level_grid_data[y][x + 1] == 0 {
-                                line_points.push(Vec2::new(
-                                    (x + 1) as f32 * grid_cell_size,
-                                    y as f32 * grid_cell_size,
-                                ));
-                                line_points.push(Vec2::new(
-                                    (x + 1) as f32 * grid_cell_size,
-                                    (y + 1) as f32 * grid_cell_size,
-                                ));
-                            }
-                        }
-                        _ => {}
-                    }
+            }
+
+            if let Some((shared_point, unique_points)) = point_removal_data {
+                let unique_vert_1 = line_points[unique_points.0];
+                let unique_vert_2 = line_points[unique_points.1];
+
+                let mut removal_indices = vec![
+                    shared_point.0,
+                    shared_point.1,
+                    unique_points.0,
+                    unique_points.1,
+                ];
+                removal_indices.sort();
+                removal_indices.reverse();
+                for i in removal_indices {
+                    line_points.remove(i);
                 }
-                6..=9 => {
-                    // // Isosceles triangles
-
-                    // let triangle_type = tile - 6;
-
-                    // match triangle_type {
-                    //     0 => {
-                    //         // Bottom
-
-                    //         // Top left
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(x as f32 * grid_size, (y + 1) as f32 * grid_size),
-                    //             Color::RED,
-                    //         ));
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(
-                    //                 (x as f32 + 0.5) as f32 * grid_size,
-                    //                 (y as f32 + 0.5) as f32 * grid_size,
-                    //             ),
-                    //             Color::RED,
-                    //         ));
-
-                    //         // Top right
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(
-                    //                 (x as f32 + 1.0) as f32 * grid_size,
-                    //                 (y + 1) as f32 * grid_size,
-                    //             ),
-                    //             Color::RED,
-                    //         ));
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(
-                    //                 (x as f32 + 0.5) as f32 * grid_size,
-                    //                 (y as f32 + 0.5) as f32 * grid_size,
-                    //             ),
-                    //             Color::RED,
-                    //         ));
-
-                    //         // Bottom edge
-                    //         if y == json_data.len() - 1 || json_data[y + 1][x] == 0 {
-                    //             line_points.push(Vertex::with_pos_color(
-                    //                 Vec2::new(x as f32 * grid_size, (y + 1) as f32 * grid_size),
-                    //                 Color::RED,
-                    //             ));
-                    //             line_points.push(Vertex::with_pos_color(
-                    //                 Vec2::new(
-                    //                     (x + 1) as f32 * grid_size,
-                    //                     (y + 1) as f32 * grid_size,
-                    //                 ),
-                    //                 Color::RED,
-                    //             ));
-                    //         }
-                    //     }
-                    //     1 => {
-                    //         // Top
-
-                    //         // Bottom left
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(x as f32 * grid_size, y as f32 * grid_size),
-                    //             Color::RED,
-                    //         ));
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(
-                    //                 (x as f32 + 0.5) as f32 * grid_size,
-                    //                 (y as f32 + 0.5) as f32 * grid_size,
-                    //             ),
-                    //             Color::RED,
-                    //         ));
-
-                    //         // Bottom right
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(
-                    //                 (x as f32 + 1.0) as f32 * grid_size,
-                    //                 y as f32 * grid_size,
-                    //             ),
-                    //             Color::RED,
-                    //         ));
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(
-                    //                 (x as f32 + 0.5) as f32 * grid_size,
-                    //                 (y as f32 + 0.5) as f32 * grid_size,
-                    //             ),
-                    //             Color::RED,
-                    //         ));
-
-                    //         // Top edge
-                    //         if y == 0 || json_data[y - 1][x] == 0 {
-                    //             line_points.push(Vertex::with_pos_color(
-                    //                 Vec2::new(x as f32 * grid_size, y as f32 * grid_size),
-                    //                 Color::RED,
-                    //             ));
-                    //             line_points.push(Vertex::with_pos_color(
-                    //                 Vec2::new((x + 1) as f32 * grid_size, y as f32 * grid_size),
-                    //                 Color::RED,
-                    //             ));
-                    //         }
-                    //     }
-                    //     2 => {
-                    //         // Left
-
-                    //         // Top right
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(x as f32 * grid_size, y as f32 * grid_size),
-                    //             Color::RED,
-                    //         ));
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(
-                    //                 (x as f32 + 0.5) as f32 * grid_size,
-                    //                 (y as f32 + 0.5) as f32 * grid_size,
-                    //             ),
-                    //             Color::RED,
-                    //         ));
-
-                    //         // Bottom right
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(x as f32 * grid_size, (y + 1) as f32 * grid_size),
-                    //             Color::RED,
-                    //         ));
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(
-                    //                 (x as f32 + 0.5) as f32 * grid_size,
-                    //                 (y as f32 + 0.5) as f32 * grid_size,
-                    //             ),
-                    //             Color::RED,
-                    //         ));
-
-                    //         // Left edge
-                    //         if x == 0 || json_data[y][x - 1] == 0 {
-                    //             line_points.push(Vertex::with_pos_color(
-                    //                 Vec2::new(x as f32 * grid_size, y as f32 * grid_size),
-                    //                 Color::RED,
-                    //             ));
-                    //             line_points.push(Vertex::with_pos_color(
-                    //                 Vec2::new(x as f32 * grid_size, (y + 1) as f32 * grid_size),
-                    //                 Color::RED,
-                    //             ));
-                    //         }
-                    //     }
-                    //     3 => {
-                    //         // Right
-
-                    //         // Top left
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new((x + 1) as f32 * grid_size, y as f32 * grid_size),
-                    //             Color::RED,
-                    //         ));
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(
-                    //                 (x as f32 + 0.5) as f32 * grid_size,
-                    //                 (y as f32 + 0.5) as f32 * grid_size,
-                    //             ),
-                    //             Color::RED,
-                    //         ));
-
-                    //         // Bottom left
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new((x + 1) as f32 * grid_size, (y + 1) as f32 * grid_size),
-                    //             Color::RED,
-                    //         ));
-                    //         line_points.push(Vertex::with_pos_color(
-                    //             Vec2::new(
-                    //                 (x as f32 + 0.5) as f32 * grid_size,
-                    //                 (y as f32 + 0.5) as f32 * grid_size,
-                    //             ),
-                    //             Color::RED,
-                    //         ));
-
-                    //         // Right edge
-                    //         if x == json_data[y].len() - 1 || json_data[y][x + 1] == 0 {
-                    //             line_points.push(Vertex::with_pos_color(
-                    //                 Vec2::new((x + 1) as f32 * grid_size, y as f32 * grid_size),
-                    //                 Color::RED,
-                    //             ));
-                    //             line_points.push(Vertex::with_pos_color(
-                    //                 Vec2::new(
-                    //                     (x + 1) as f32 * grid_size,
-                    //                     (y + 1) as f32 * grid_size,
-                    //                 ),
-                    //                 Color::RED,
-                    //             ));
-                    //         }
-                    //     }
-                    //     _ => {}
-                    // }
+
+                line_points.push(unique_vert_1);
+                line_points.push(unique_vert_2);
+
+                line_count -= 1;
+            }
+        }
+
+        // Separate the lines into polygons
+        let mut outlines = Vec::new();
+
+        while line_count > 0 {
+            let mut outline = vec![line_points[0], line_points[1]];
+
+            line_points.drain(0..2);
+            line_count -= 1;
+
+            let start_vert = outline[0];
+            let mut current_vert = outline[1];
+
+            while start_vert != current_vert {
+                let i = (0..line_points.len() / 2)
+                    .find(|i| {
+                        line_points[i * 2] == current_vert || line_points[i * 2 + 1] == current_vert
+                    })
+                    .expect("outline does not close");
+
+                current_vert = if line_points[i * 2] == current_vert {
+                    line_points[i * 2 + 1]
+                } else {
+                    line_points[i * 2]
+                };
+                outline.push(current_vert);
+
+                line_points.drain(i * 2..i * 2 + 2);
+                line_count -= 1;
+            }
+
+            outlines.push(outline);
+        }
+
+        outlines
+    }
+
+    /// Puts outlines in a form that doesn't depend on their order,
+    /// where they start, or which way they wind
+    fn canonical_outlines(outlines: &[Vec<Vec2>]) -> Vec<Vec<(f32, f32)>> {
+        let mut canonical: Vec<Vec<(f32, f32)>> = outlines
+            .iter()
+            .map(|outline| {
+                let points: Vec<(f32, f32)> = outline[..outline.len() - 1]
+                    .iter()
+                    .map(|point| (point.x, point.y))
+                    .collect();
+
+                let rotate_to_smallest = |mut points: Vec<(f32, f32)>| {
+                    let smallest_index = (0..points.len())
+                        .min_by(|a, b| points[*a].partial_cmp(&points[*b]).unwrap())
+                        .unwrap();
+                    points.rotate_left(smallest_index);
+                    points
+                };
+
+                let forwards = rotate_to_smallest(points.clone());
+                let backwards = rotate_to_smallest(points.into_iter().rev().collect());
+
+                if forwards.partial_cmp(&backwards) == Some(std::cmp::Ordering::Less) {
+                    forwards
+                } else {
+                    backwards
+                }
+            })
+            .collect();
+
+        canonical.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        canonical
+    }
+
+    fn get_test_tileset() -> Tileset {
+        Tileset::from_bytes(include_bytes!("../../assets/tiles.tileset.json")).unwrap()
+    }
+
+    fn assert_outlines_match_reference(level_grid_data: &[Vec<usize>]) {
+        let tileset = get_test_tileset();
+
+        let size = Vec2::new(
+            level_grid_data[0].len() as f32,
+            level_grid_data.len() as f32,
+        );
+
+        let line_points = get_line_points(level_grid_data, &tileset, 32.0, size);
+
+        let outlines = trace_outlines(&line_points).unwrap();
+        let reference_outlines = trace_outlines_reference(&line_points);
+
+        assert_eq!(
+            canonical_outlines(&outlines),
+            canonical_outlines(&reference_outlines)
+        );
+    }
+
+    #[test]
+    fn level_json_outlines_match_reference() {
+        let level_data = LevelData::from_bytes(include_bytes!("../../assets/level.json")).unwrap();
+
+        let LevelGeometry::Grid(level_grid_data) = &level_data.geometry else {
+            panic!("level.json should be a tile grid");
+        };
+
+        assert_outlines_match_reference(level_grid_data);
+    }
+
+    #[test]
+    fn every_tile_outline_matches_reference() {
+        // Every tile in the tileset on its own, and the tiles that are made to fit together.
+        // Tiles only touching at a corner are left out, since there's more than one
+        // right way to split an outline that touches itself.
+        let level_grid_data: Vec<Vec<usize>> = vec![
+            vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+            vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            vec![1, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6, 0, 7, 0, 8, 0, 9, 0, 1],
+            vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            vec![
+                1, 0, 10, 1, 0, 11, 1, 0, 12, 13, 1, 0, 1, 14, 15, 0, 0, 0, 1,
+            ],
+            vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+        ];
+
+        assert_outlines_match_reference(&level_grid_data);
+    }
+
+    #[test]
+    fn isosceles_triangles_are_shallower_than_45_degrees() {
+        // Each of the isosceles triangles on its own, away from the walls of the room
+        let level_grid_data: Vec<Vec<usize>> = vec![
+            vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+            vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            vec![1, 0, 6, 0, 7, 0, 8, 0, 9, 1],
+            vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+        ];
+
+        let level = Level::from_grid(&level_grid_data, &get_test_tileset(), 32.0).unwrap();
+
+        // The sides of the triangles rise half a cell over a whole cell, like the quarter slopes
+        let slope_angle = 0.5_f32.atan();
+
+        let mut sloped_line_count = 0;
+
+        for polygon in &level.polygons {
+            for line in polygon.points.windows(2) {
+                let line = (line[1] - line[0]).abs();
+
+                if line.x == 0.0 || line.y == 0.0 {
+                    continue;
+                }
+
+                // The angle from the side of the cell the triangle sits on
+                let angle = line.y.min(line.x).atan2(line.y.max(line.x));
+
+                assert!(
+                    (angle - slope_angle).abs() < 0.0001,
+                    "{}",
+                    angle.to_degrees()
+                );
+
+                sloped_line_count += 1;
+            }
+        }
+
+        assert_eq!(sloped_line_count, 8);
+    }
+
+    fn get_test_box_level() -> Level {
+        // A 4 x 4 room with a 2 x 1 block in the middle of it
+        let level_grid_data: Vec<Vec<usize>> = vec![
+            vec![1, 1, 1, 1, 1, 1],
+            vec![1, 0, 0, 0, 0, 1],
+            vec![1, 0, 1, 1, 0, 1],
+            vec![1, 0, 0, 0, 0, 1],
+            vec![1, 0, 0, 0, 0, 1],
+            vec![1, 1, 1, 1, 1, 1],
+        ];
+
+        Level::from_grid(&level_grid_data, &get_test_tileset(), 32.0).unwrap()
+    }
+
+    #[test]
+    fn raycast_hits_the_closest_line() {
+        let level = get_test_box_level();
+
+        // Straight up from the floor into the bottom of the block
+        let hit = level
+            .raycast(Vec2::new(0.0, -60.0), Vec2::new(0.0, 1.0), 1000.0)
+            .unwrap();
+
+        assert_eq!(hit.point, Vec2::new(0.0, 0.0));
+        assert_eq!(hit.normal, Vec2::new(0.0, -1.0));
+        assert_eq!(hit.distance, 60.0);
+
+        let (start, end) = level
+            .get_line(hit.polygon_line.polygon_index, hit.polygon_line.line_index)
+            .unwrap();
+        assert_eq!(start.y, 0.0);
+        assert_eq!(end.y, 0.0);
+
+        // Too short to reach the block
+        assert!(level
+            .raycast(Vec2::new(0.0, -60.0), Vec2::new(0.0, 1.0), 50.0)
+            .is_none());
+    }
+
+    #[test]
+    fn line_of_sight_is_blocked_by_the_level() {
+        let level = get_test_box_level();
+
+        // Under the block
+        assert!(level.line_of_sight_check(Vec2::new(-48.0, -16.0), Vec2::new(48.0, -16.0)));
+        // Through the block
+        assert!(!level.line_of_sight_check(Vec2::new(-48.0, 16.0), Vec2::new(48.0, 16.0)));
+        // Through the walls of the room
+        assert!(!level.line_of_sight_check(Vec2::new(0.0, -48.0), Vec2::new(0.0, -200.0)));
+    }
+
+    #[test]
+    fn circle_cast_hits_corners_between_its_edges() {
+        let level = get_test_box_level();
+
+        // Passes just under the bottom left corner of the block at (-32, 0),
+        // so a ray along its center misses, but the circle clips the corner
+        let start = Vec2::new(-56.0, -4.0);
+        let end = Vec2::new(-16.0, -4.0);
+
+        assert!(level.raycast(start, end - start, 40.0).is_none());
+
+        let hit = level.circle_cast(start, end, 8.0).unwrap();
+
+        assert_eq!(hit.point, Vec2::new(-32.0, 0.0));
+        assert!((hit.normal.length() - 1.0).abs() < 0.0001);
+        assert!(hit.normal.x < 0.0 && hit.normal.y < 0.0);
+
+        // Where the circle is when it touches the corner
+        let center = start + (end - start) * hit.time_of_impact;
+        assert!(((center - hit.point).length() - 8.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn circle_cast_can_leave_a_surface() {
+        let level = get_test_box_level();
+
+        // Resting on the floor of the room at y = -64
+        let start = Vec2::new(0.0, -56.0);
+
+        assert!(level
+            .circle_cast(start, start + Vec2::new(0.0, 40.0), 8.0)
+            .is_none());
+        assert!(level
+            .circle_cast(start, start + Vec2::new(20.0, 0.0), 8.0)
+            .is_none());
+
+        let hit = level
+            .circle_cast(start, start + Vec2::new(0.0, -20.0), 8.0)
+            .unwrap();
+        assert_eq!(hit.time_of_impact, 0.0);
+        assert_eq!(hit.normal, Vec2::new(0.0, 1.0));
+    }
+
+    #[test]
+    fn segment_index_finds_every_crossing_line() {
+        let level_data = LevelData::from_bytes(include_bytes!("../../assets/level.json")).unwrap();
+        let level = Level::from_level_data(&level_data, &get_test_tileset(), 32.0).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(13);
+
+        for _ in 0..500 {
+            let start = (Vec2::new(rng.gen(), rng.gen()) - 0.5) * level.size * 32.0 * 1.2;
+            let end = (Vec2::new(rng.gen(), rng.gen()) - 0.5) * level.size * 32.0 * 1.2;
+
+            let nearby_lines = level.segment_index.query_segment(start, end);
+
+            for (polygon_index, polygon) in level.polygons.iter().enumerate() {
+                for line_index in 0..polygon.points.len() - 1 {
+                    let polygon_line = PolygonLine {
+                        polygon_index,
+                        line_index,
+                    };
+                    let (line_start, line_end) = level.get_polygon_line(&polygon_line);
+
+                    if line_intersect(line_start, line_end, start, end).is_some() {
+                        assert!(
+                            nearby_lines.contains(&polygon_line),
+                            "{:?} crosses {} -> {} but wasn't found",
+                            polygon_line,
+                            start,
+                            end
+                        );
+                    }
                 }
-                _ => {}
             }
         }
     }
 
-    return line_points;
+    #[test]
+    fn shortcut_needs_two_entrances() {
+        let level_data = LevelData::from_bytes(
+            br#"{
+                "polygons": [{ "points": [[-100, -50], [100, -50], [100, 50], [-100, 50]], "is_container": true }],
+                "shortcuts": [{ "entrances": [[-90, -45], [90, -45]] }, { "entrances": [[0, -45]] }]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            Level::from_level_data(&level_data, &get_test_tileset(), 32.0),
+            Err(LevelLoadError::InvalidShortcut { shortcut: 1 })
+        ));
+    }
+
+    #[test]
+    fn malformed_json_is_an_error() {
+        // The second row is missing its closing bracket
+        let result = LevelData::from_bytes(b"[\n  [1, 1],\n  [1, 1\n]");
+
+        let Err(LevelLoadError::MalformedJson(error)) = result else {
+            panic!("expected MalformedJson, got {:?}", result.map(|_| ()));
+        };
+
+        assert_eq!((error.line(), error.column()), (4, 1));
+    }
+
+    #[test]
+    fn empty_grid_is_an_error() {
+        let tileset = get_test_tileset();
+
+        assert!(matches!(
+            Level::from_grid(&[], &tileset, 32.0),
+            Err(LevelLoadError::EmptyGrid)
+        ));
+        assert!(matches!(
+            Level::from_grid(&[vec![], vec![]], &tileset, 32.0),
+            Err(LevelLoadError::EmptyGrid)
+        ));
+    }
+
+    #[test]
+    fn ragged_row_is_an_error() {
+        let level_grid_data: Vec<Vec<usize>> = vec![
+            vec![1, 1, 1, 1],
+            vec![1, 0, 0, 1],
+            vec![1, 0, 1],
+            vec![1, 1, 1, 1],
+        ];
+
+        assert!(matches!(
+            Level::from_grid(&level_grid_data, &get_test_tileset(), 32.0),
+            Err(LevelLoadError::RaggedRow {
+                row: 2,
+                expected_length: 4,
+                length: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_tile_is_an_error() {
+        let level_grid_data: Vec<Vec<usize>> = vec![
+            vec![1, 1, 1, 1],
+            vec![1, 0, 0, 1],
+            vec![1, 0, 99, 1],
+            vec![1, 1, 1, 1],
+        ];
+
+        assert!(matches!(
+            Level::from_grid(&level_grid_data, &get_test_tileset(), 32.0),
+            Err(LevelLoadError::UnknownTile {
+                x: 2,
+                y: 2,
+                tile: 99
+            })
+        ));
+    }
+
+    #[test]
+    fn unclosed_outline_is_an_error() {
+        // Tile 2 is almost a square, but its top edge sits a tiny bit below the top of the cell.
+        // It's still close enough to count as covered by the square above it, so that edge is
+        // left out, but its corners don't meet the corners of the square.
+        let tileset = Tileset::from_bytes(
+            br#"{
+                "tiles": {
+                    "1": { "outline": [[0, 0], [1, 0], [1, 1], [0, 1]] },
+                    "2": { "outline": [[0, 0.00005], [1, 0.00005], [1, 1], [0, 1]] }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let level_grid_data: Vec<Vec<usize>> = vec![vec![0, 1, 0], vec![0, 2, 0]];
+
+        let result = Level::from_grid(&level_grid_data, &tileset, 32.0);
+
+        let Err(LevelLoadError::UnclosedOutline { position }) = result else {
+            panic!("expected UnclosedOutline, got {:?}", result.map(|_| ()));
+        };
+
+        // The bottom right corner of the square, where its right edge has nothing to join up with
+        assert_eq!(position, Vec2::new(2.0, 1.0));
+    }
+
+    #[test]
+    fn open_outline_is_an_error() {
+        // A single line can never be closed
+        let line_points = vec![Vec2::new(0.0, 0.0), Vec2::new(32.0, 0.0)];
+
+        assert_eq!(trace_outlines(&line_points), Err(Vec2::new(32.0, 0.0)));
+    }
 }
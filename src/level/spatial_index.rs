@@ -0,0 +1,269 @@
+use bevy::math::Vec2;
+
+use super::PolygonLine;
+
+/// How far outside of a cell a line can be and still be put in it.
+/// This makes sure lines that lie on the border between cells are in both of them.
+const CELL_EPSILON: f32 = 0.01;
+
+/// A uniform grid over the lines of a level, so geometry queries only have to look at
+/// the lines near them instead of every line in the level
+pub struct SegmentIndex {
+    cell_size: f32,
+    /// The bottom left corner of the grid
+    min: Vec2,
+    /// The top right corner of the grid
+    max: Vec2,
+    columns: usize,
+    rows: usize,
+    /// The lines that overlap each cell, row by row from the bottom
+    cells: Vec<Vec<PolygonLine>>,
+}
+
+impl SegmentIndex {
+    /// Builds the index over closed polygons, where line `i` goes from point `i` to point `i + 1`
+    pub fn new<'a>(polygons: impl IntoIterator<Item = &'a [Vec2]>, cell_size: f32) -> SegmentIndex {
+        let polygons: Vec<&[Vec2]> = polygons.into_iter().collect();
+
+        let mut min = Vec2::splat(f32::MAX);
+        let mut max = Vec2::splat(f32::MIN);
+
+        for point in polygons.iter().flat_map(|points| points.iter()) {
+            min = min.min(*point);
+            max = max.max(*point);
+        }
+
+        if min.x > max.x {
+            // No points at all
+            min = Vec2::ZERO;
+            max = Vec2::ZERO;
+        }
+
+        // Leave a cell of space around the lines, so nothing sits on the edge of the grid
+        min -= Vec2::splat(cell_size);
+        max += Vec2::splat(cell_size);
+
+        let columns = ((max.x - min.x) / cell_size).ceil() as usize;
+        let rows = ((max.y - min.y) / cell_size).ceil() as usize;
+
+        let mut segment_index = SegmentIndex {
+            cell_size,
+            min,
+            max: min + Vec2::new(columns as f32, rows as f32) * cell_size,
+            columns,
+            rows,
+            cells: vec![Vec::new(); columns * rows],
+        };
+
+        for (polygon_index, points) in polygons.iter().enumerate() {
+            for line_index in 0..points.len().saturating_sub(1) {
+                segment_index.insert(
+                    PolygonLine {
+                        polygon_index,
+                        line_index,
+                    },
+                    points[line_index],
+                    points[line_index + 1],
+                );
+            }
+        }
+
+        segment_index
+    }
+
+    fn insert(&mut self, polygon_line: PolygonLine, start: Vec2, end: Vec2) {
+        let (min_cell_x, min_cell_y) = self.get_cell(start.min(end) - Vec2::splat(CELL_EPSILON));
+        let (max_cell_x, max_cell_y) = self.get_cell(start.max(end) + Vec2::splat(CELL_EPSILON));
+
+        // Only the cells in the line's bounding box that the line actually goes through
+        for cell_y in min_cell_y..=max_cell_y {
+            for cell_x in min_cell_x..=max_cell_x {
+                let cell_min = self.min + Vec2::new(cell_x as f32, cell_y as f32) * self.cell_size;
+                let cell_max = cell_min + Vec2::splat(self.cell_size);
+
+                let line_in_cell = clip_segment(
+                    start,
+                    end,
+                    cell_min - Vec2::splat(CELL_EPSILON),
+                    cell_max + Vec2::splat(CELL_EPSILON),
+                )
+                .is_some();
+
+                if line_in_cell {
+                    self.cells[cell_y * self.columns + cell_x].push(polygon_line.clone());
+                }
+            }
+        }
+    }
+
+    /// The length of the diagonal of the whole grid.
+    /// A ray this long will always leave the level.
+    pub fn get_diagonal_length(&self) -> f32 {
+        (self.max - self.min).length()
+    }
+
+    /// Which cell a point is in, clamped to the grid
+    fn get_cell(&self, point: Vec2) -> (usize, usize) {
+        let cell = ((point - self.min) / self.cell_size).floor();
+
+        (
+            (cell.x.max(0.0) as usize).min(self.columns.saturating_sub(1)),
+            (cell.y.max(0.0) as usize).min(self.rows.saturating_sub(1)),
+        )
+    }
+
+    /// Every line that might overlap the box from `min` to `max`, sorted and without duplicates
+    pub fn query_aabb(&self, min: Vec2, max: Vec2) -> Vec<PolygonLine> {
+        let mut polygon_lines = Vec::new();
+
+        if self.cells.is_empty() || max.x < self.min.x || max.y < self.min.y {
+            return polygon_lines;
+        }
+        if min.x > self.max.x || min.y > self.max.y {
+            return polygon_lines;
+        }
+
+        let (min_cell_x, min_cell_y) = self.get_cell(min);
+        let (max_cell_x, max_cell_y) = self.get_cell(max);
+
+        for cell_y in min_cell_y..=max_cell_y {
+            for cell_x in min_cell_x..=max_cell_x {
+                polygon_lines.extend_from_slice(&self.cells[cell_y * self.columns + cell_x]);
+            }
+        }
+
+        polygon_lines.sort();
+        polygon_lines.dedup();
+
+        polygon_lines
+    }
+
+    /// Every line that might cross the segment from `start` to `end`, sorted and without duplicates
+    pub fn query_segment(&self, start: Vec2, end: Vec2) -> Vec<PolygonLine> {
+        let mut polygon_lines = Vec::new();
+
+        if self.cells.is_empty() {
+            return polygon_lines;
+        }
+
+        // Only the part of the segment inside the grid can hit anything
+        let Some((start, end)) = clip_segment(start, end, self.min, self.max) else {
+            return polygon_lines;
+        };
+
+        // Walk along the cells the segment goes through
+        let (mut cell_x, mut cell_y) = self.get_cell(start);
+        let (end_cell_x, end_cell_y) = self.get_cell(end);
+
+        let delta = end - start;
+
+        let step_x: isize = if delta.x > 0.0 { 1 } else { -1 };
+        let step_y: isize = if delta.y > 0.0 { 1 } else { -1 };
+
+        // How far along the segment the next cell border is, from 0 at `start` to 1 at `end`
+        let get_next_border = |cell: usize, start: f32, delta: f32, min: f32| {
+            if delta == 0.0 {
+                return f32::MAX;
+            }
+
+            let border_cell = if delta > 0.0 { cell + 1 } else { cell };
+            let border = min + border_cell as f32 * self.cell_size;
+
+            (border - start) / delta
+        };
+
+        let mut next_border_x = get_next_border(cell_x, start.x, delta.x, self.min.x);
+        let mut next_border_y = get_next_border(cell_y, start.y, delta.y, self.min.y);
+
+        let border_step_x = if delta.x == 0.0 {
+            f32::MAX
+        } else {
+            self.cell_size / delta.x.abs()
+        };
+        let border_step_y = if delta.y == 0.0 {
+            f32::MAX
+        } else {
+            self.cell_size / delta.y.abs()
+        };
+
+        loop {
+            polygon_lines.extend_from_slice(&self.cells[cell_y * self.columns + cell_x]);
+
+            if cell_x == end_cell_x && cell_y == end_cell_y {
+                break;
+            }
+
+            if next_border_x < next_border_y {
+                if next_border_x > 1.0 {
+                    break;
+                }
+
+                let Some(next_cell_x) = cell_x.checked_add_signed(step_x) else {
+                    break;
+                };
+                if next_cell_x >= self.columns {
+                    break;
+                }
+
+                cell_x = next_cell_x;
+                next_border_x += border_step_x;
+            } else {
+                if next_border_y > 1.0 {
+                    break;
+                }
+
+                let Some(next_cell_y) = cell_y.checked_add_signed(step_y) else {
+                    break;
+                };
+                if next_cell_y >= self.rows {
+                    break;
+                }
+
+                cell_y = next_cell_y;
+                next_border_y += border_step_y;
+            }
+        }
+
+        polygon_lines.sort();
+        polygon_lines.dedup();
+
+        polygon_lines
+    }
+}
+
+/// Cuts a segment down to the part of it inside a box, if there is any
+fn clip_segment(start: Vec2, end: Vec2, min: Vec2, max: Vec2) -> Option<(Vec2, Vec2)> {
+    let delta = end - start;
+
+    let mut t_start: f32 = 0.0;
+    let mut t_end: f32 = 1.0;
+
+    for (start, delta, min, max) in [
+        (start.x, delta.x, min.x, max.x),
+        (start.y, delta.y, min.y, max.y),
+    ] {
+        if delta == 0.0 {
+            if start < min || start > max {
+                return None;
+            }
+
+            continue;
+        }
+
+        let mut t_min = (min - start) / delta;
+        let mut t_max = (max - start) / delta;
+
+        if t_min > t_max {
+            std::mem::swap(&mut t_min, &mut t_max);
+        }
+
+        t_start = t_start.max(t_min);
+        t_end = t_end.min(t_max);
+
+        if t_start > t_end {
+            return None;
+        }
+    }
+
+    Some((start + delta * t_start, start + delta * t_end))
+}
@@ -0,0 +1,364 @@
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    math::Vec2,
+    utils::BoxedFuture,
+};
+use serde::{de::IgnoredAny, Deserialize};
+
+use super::{
+    error::LevelLoadError,
+    loader::{LevelData, LevelGeometry, ShortcutData},
+};
+
+/// The tile layer that is used as the collision grid
+const COLLISION_LAYER_NAME: &str = "collision";
+
+/// The object layer the agent spawn points are read from
+const SPAWN_LAYER_NAME: &str = "spawns";
+
+/// The object layer the goal positions are read from
+const GOAL_LAYER_NAME: &str = "goals";
+
+/// The object layer the shortcuts are read from. Each shortcut is a polyline,
+/// with an entrance at each of its points.
+const SHORTCUT_LAYER_NAME: &str = "shortcuts";
+
+/// Tiled stores whether a tile is flipped in the top bits of its gid
+const TILED_FLIP_FLAGS: u32 = 0xF000_0000;
+
+/// The custom tile property that sets which tile id from the tileset file a Tiled tile becomes
+const TILE_ID_PROPERTY_NAME: &str = "tile_id";
+
+/// A map saved by Tiled in its JSON format (`.tmj`).
+/// Only the parts that are needed to build a level are read.
+#[derive(Deserialize)]
+struct TiledMap {
+    #[serde(default)]
+    infinite: bool,
+    tilewidth: f32,
+    tileheight: f32,
+    layers: Vec<TiledLayer>,
+    #[serde(default)]
+    tilesets: Vec<TiledTilesetReference>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum TiledLayer {
+    TileLayer {
+        name: String,
+        width: usize,
+        height: usize,
+        /// Missing when the layer is split into chunks
+        data: Option<TiledLayerData>,
+    },
+    ObjectGroup {
+        name: String,
+        objects: Vec<TiledObject>,
+    },
+    Group {
+        layers: Vec<TiledLayer>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TiledLayerData {
+    Csv(Vec<u32>),
+    /// Base64, possibly compressed. The importer doesn't decode these.
+    Encoded(IgnoredAny),
+}
+
+#[derive(Deserialize)]
+struct TiledObject {
+    x: f32,
+    y: f32,
+    #[serde(default)]
+    width: f32,
+    #[serde(default)]
+    height: f32,
+    /// Only set on tile objects, which are positioned by their bottom left corner
+    gid: Option<u32>,
+    /// The points of a polyline object, relative to the object's position
+    #[serde(default)]
+    polyline: Vec<TiledPoint>,
+}
+
+#[derive(Deserialize)]
+struct TiledPoint {
+    x: f32,
+    y: f32,
+}
+
+#[derive(Deserialize)]
+struct TiledTilesetReference {
+    firstgid: u32,
+    /// The tiles with properties. External tilesets don't list any.
+    #[serde(default)]
+    tiles: Vec<TiledTile>,
+}
+
+#[derive(Deserialize)]
+struct TiledTile {
+    id: u32,
+    #[serde(default)]
+    properties: Vec<TiledProperty>,
+}
+
+#[derive(Deserialize)]
+struct TiledProperty {
+    name: String,
+    value: serde_json::Value,
+}
+
+impl TiledMap {
+    /// Every layer in the map, with the layers inside of groups in the order Tiled draws them
+    fn get_layers(&self) -> Vec<&TiledLayer> {
+        fn push_layers<'a>(layers: &'a [TiledLayer], all_layers: &mut Vec<&'a TiledLayer>) {
+            for layer in layers {
+                if let TiledLayer::Group { layers } = layer {
+                    push_layers(layers, all_layers);
+                } else {
+                    all_layers.push(layer);
+                }
+            }
+        }
+
+        let mut all_layers = Vec::new();
+        push_layers(&self.layers, &mut all_layers);
+
+        all_layers
+    }
+
+    /// Turns a gid from a tile layer into a tile id.
+    /// A tile with a `tile_id` property becomes that tile id. Otherwise tile N of the first
+    /// tileset becomes tile id N + 1, since 0 is always empty. Tiles from the other tilesets
+    /// have to set `tile_id`, since their numbers would clash with the first tileset's.
+    fn get_tile_id(&self, gid: u32) -> Result<usize, LevelLoadError> {
+        let gid = gid & !TILED_FLIP_FLAGS;
+
+        if gid == 0 {
+            return Ok(0);
+        }
+
+        // The tileset a gid belongs to is the last one that starts at or before it
+        let Some(tileset) = self
+            .tilesets
+            .iter()
+            .filter(|tileset| tileset.firstgid <= gid)
+            .max_by_key(|tileset| tileset.firstgid)
+        else {
+            return Err(LevelLoadError::UnsupportedTiledMap {
+                reason: "the collision layer uses a tile that isn't in any of the map's tilesets",
+            });
+        };
+
+        let local_id = gid - tileset.firstgid;
+
+        let tile_id_property = tileset
+            .tiles
+            .iter()
+            .filter(|tile| tile.id == local_id)
+            .flat_map(|tile| &tile.properties)
+            .find(|property| property.name == TILE_ID_PROPERTY_NAME);
+
+        if let Some(property) = tile_id_property {
+            return property
+                .value
+                .as_u64()
+                .map(|tile_id| tile_id as usize)
+                .ok_or(LevelLoadError::UnsupportedTiledMap {
+                    reason: "tile_id properties have to be whole numbers",
+                });
+        }
+
+        let is_first_tileset = self
+            .tilesets
+            .iter()
+            .all(|other_tileset| other_tileset.firstgid >= tileset.firstgid);
+
+        if !is_first_tileset {
+            return Err(LevelLoadError::UnsupportedTiledMap {
+                reason: "tiles from every tileset but the first need a tile_id property",
+            });
+        }
+
+        Ok(local_id as usize + 1)
+    }
+
+    /// Reads the objects of an object layer as positions in grid cells.
+    /// Rectangles and tiles are read as their center.
+    fn get_object_positions(&self, layer_name: &str) -> Vec<Vec2> {
+        let mut positions = Vec::new();
+
+        for layer in self.get_layers() {
+            if let TiledLayer::ObjectGroup { name, objects } = layer {
+                if name != layer_name {
+                    continue;
+                }
+
+                for object in objects {
+                    // Tiled puts the origin of tile objects at their bottom left,
+                    // and of everything else at their top left
+                    let center_y = if object.gid.is_some() {
+                        object.y - object.height / 2.0
+                    } else {
+                        object.y + object.height / 2.0
+                    };
+
+                    let center = Vec2::new(object.x + object.width / 2.0, center_y);
+
+                    positions.push(center / Vec2::new(self.tilewidth, self.tileheight));
+                }
+            }
+        }
+
+        positions
+    }
+
+    /// Reads the polylines of an object layer as shortcuts, in grid cells.
+    /// Objects that aren't polylines are skipped.
+    fn get_shortcuts(&self, layer_name: &str) -> Vec<ShortcutData> {
+        let mut shortcuts = Vec::new();
+
+        for layer in self.get_layers() {
+            if let TiledLayer::ObjectGroup { name, objects } = layer {
+                if name != layer_name {
+                    continue;
+                }
+
+                for object in objects {
+                    if object.polyline.is_empty() {
+                        continue;
+                    }
+
+                    let entrances = object
+                        .polyline
+                        .iter()
+                        .map(|point| {
+                            Vec2::new(object.x + point.x, object.y + point.y)
+                                / Vec2::new(self.tilewidth, self.tileheight)
+                        })
+                        .collect();
+
+                    shortcuts.push(ShortcutData { entrances });
+                }
+            }
+        }
+
+        shortcuts
+    }
+}
+
+impl LevelData {
+    pub fn from_tiled_bytes(bytes: &[u8]) -> Result<LevelData, LevelLoadError> {
+        let tiled_map: TiledMap = serde_json::from_slice(bytes)?;
+
+        if tiled_map.infinite {
+            return Err(LevelLoadError::UnsupportedTiledMap {
+                reason: "infinite maps can't be used as levels",
+            });
+        }
+
+        let (width, height, data) = tiled_map
+            .get_layers()
+            .into_iter()
+            .find_map(|layer| match layer {
+                TiledLayer::TileLayer {
+                    name,
+                    width,
+                    height,
+                    data,
+                } if name == COLLISION_LAYER_NAME => Some((*width, *height, data)),
+                _ => None,
+            })
+            .ok_or(LevelLoadError::MissingTiledLayer {
+                name: COLLISION_LAYER_NAME,
+            })?;
+
+        let Some(TiledLayerData::Csv(data)) = data else {
+            return Err(LevelLoadError::UnsupportedTiledMap {
+                reason: "the collision layer has to use the CSV tile layer format",
+            });
+        };
+
+        if width == 0 || data.len() != width * height {
+            return Err(LevelLoadError::UnsupportedTiledMap {
+                reason: "the collision layer's size doesn't match its data",
+            });
+        }
+
+        let grid = data
+            .chunks(width)
+            .map(|row| row.iter().map(|gid| tiled_map.get_tile_id(*gid)).collect())
+            .collect::<Result<_, _>>()?;
+
+        Ok(LevelData {
+            geometry: LevelGeometry::Grid(grid),
+            spawn_points: tiled_map.get_object_positions(SPAWN_LAYER_NAME),
+            goal_positions: tiled_map.get_object_positions(GOAL_LAYER_NAME),
+            shortcuts: tiled_map.get_shortcuts(SHORTCUT_LAYER_NAME),
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct TiledMapLoader;
+
+impl AssetLoader for TiledMapLoader {
+    type Asset = LevelData;
+    type Settings = ();
+    type Error = LevelLoadError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<LevelData, LevelLoadError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+
+            LevelData::from_tiled_bytes(&bytes)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["tmj"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_map_is_imported() {
+        let level_data =
+            LevelData::from_tiled_bytes(include_bytes!("fixtures/small_map.tmj")).unwrap();
+
+        let LevelGeometry::Grid(grid) = &level_data.geometry else {
+            panic!("Tiled maps should be tile grids");
+        };
+
+        // The flipped square in the corner is still a square, and the tile from the second
+        // tileset uses its tile_id property
+        assert_eq!(
+            grid,
+            &vec![
+                vec![1, 1, 1, 1, 1],
+                vec![1, 0, 0, 0, 1],
+                vec![1, 0, 0, 0, 1],
+                vec![1, 1, 10, 1, 1],
+            ]
+        );
+
+        // The spawn is a tile object, which Tiled positions by its bottom left corner
+        assert_eq!(level_data.spawn_points, vec![Vec2::new(1.5, 2.5)]);
+        // The goal is a rectangle inside of a group layer
+        assert_eq!(level_data.goal_positions, vec![Vec2::new(3.5, 2.5)]);
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::{io::Reader, Asset, AssetLoader, AsyncReadExt, LoadContext},
+    math::Vec2,
+    reflect::TypePath,
+    utils::BoxedFuture,
+};
+use serde::Deserialize;
+
+use super::error::LevelLoadError;
+
+/// How close a point has to be to the side of a cell to count as being on it
+const SIDE_EPSILON: f32 = 0.0001;
+
+/// Maps tile ids to their shapes. Tile id 0 is always empty.
+#[derive(Asset, TypePath, Debug, Deserialize)]
+pub struct Tileset {
+    pub tiles: HashMap<usize, TileDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TileDefinition {
+    #[serde(default)]
+    pub name: String,
+    /// The outline of the tile, in cell-local coordinates.
+    /// (0, 0) is the top left of the cell and (1, 1) is the bottom right.
+    pub outline: Vec<Vec2>,
+    #[serde(default)]
+    pub flags: TileFlags,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TileFlags {
+    /// Non-solid tiles have no collision and don't hide the edges of the tiles next to them
+    pub solid: bool,
+}
+
+impl Default for TileFlags {
+    fn default() -> Self {
+        TileFlags { solid: true }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TileSide {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+impl TileSide {
+    fn opposite(self) -> TileSide {
+        match self {
+            TileSide::Top => TileSide::Bottom,
+            TileSide::Bottom => TileSide::Top,
+            TileSide::Left => TileSide::Right,
+            TileSide::Right => TileSide::Left,
+        }
+    }
+}
+
+impl Tileset {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Tileset, LevelLoadError> {
+        let tileset: Tileset = serde_json::from_slice(bytes)?;
+
+        for (tile, tile_definition) in tileset.tiles.iter() {
+            let outline_in_cell = tile_definition
+                .outline
+                .iter()
+                .all(|point| (0.0..=1.0).contains(&point.x) && (0.0..=1.0).contains(&point.y));
+
+            if *tile == 0 || tile_definition.outline.len() < 3 || !outline_in_cell {
+                return Err(LevelLoadError::InvalidTileDefinition { tile: *tile });
+            }
+        }
+
+        Ok(tileset)
+    }
+
+    pub fn contains(&self, tile: usize) -> bool {
+        tile == 0 || self.tiles.contains_key(&tile)
+    }
+
+    /// Returns the solid definition of a tile, if it has one
+    fn get_solid_tile(&self, tile: usize) -> Option<&TileDefinition> {
+        self.tiles
+            .get(&tile)
+            .filter(|tile_definition| tile_definition.flags.solid)
+    }
+
+    /// Adds the edges of the tile at (x, y) to `line_points`, in cell units.
+    /// Parts of edges that are covered by a neighboring tile are left out.
+    pub fn push_tile_edges(
+        &self,
+        level_grid_data: &[Vec<usize>],
+        x: usize,
+        y: usize,
+        line_points: &mut Vec<Vec2>,
+    ) {
+        let Some(tile_definition) = self.get_solid_tile(level_grid_data[y][x]) else {
+            return;
+        };
+
+        let cell_position = Vec2::new(x as f32, y as f32);
+        let outline = &tile_definition.outline;
+
+        for point_index in 0..outline.len() {
+            let start = outline[point_index];
+            let end = outline[(point_index + 1) % outline.len()];
+
+            let Some(side) = get_edge_side(start, end) else {
+                // Edges inside the cell are always part of an outline
+                line_points.push(cell_position + start);
+                line_points.push(cell_position + end);
+                continue;
+            };
+
+            let neighbor_coverage = match self.get_neighbor(level_grid_data, x, y, side) {
+                Some(neighbor) => get_side_coverage(neighbor, side.opposite()),
+                None => Vec::new(),
+            };
+
+            // Walk along the edge, only keeping the parts the neighbor doesn't cover
+            let (edge_start, edge_end) = get_side_interval(start, end, side);
+
+            let mut uncovered_start = edge_start;
+
+            for (covered_start, covered_end) in neighbor_coverage {
+                if covered_end <= uncovered_start || covered_start >= edge_end {
+                    continue;
+                }
+
+                if covered_start > uncovered_start {
+                    push_side_edge(
+                        cell_position,
+                        side,
+                        uncovered_start,
+                        covered_start,
+                        line_points,
+                    );
+                }
+
+                uncovered_start = uncovered_start.max(covered_end);
+            }
+
+            if uncovered_start < edge_end {
+                push_side_edge(cell_position, side, uncovered_start, edge_end, line_points);
+            }
+        }
+    }
+
+    fn get_neighbor(
+        &self,
+        level_grid_data: &[Vec<usize>],
+        x: usize,
+        y: usize,
+        side: TileSide,
+    ) -> Option<&TileDefinition> {
+        let neighbor = match side {
+            TileSide::Top if y > 0 => level_grid_data[y - 1].get(x),
+            TileSide::Bottom => level_grid_data.get(y + 1).and_then(|row| row.get(x)),
+            TileSide::Left if x > 0 => level_grid_data[y].get(x - 1),
+            TileSide::Right => level_grid_data[y].get(x + 1),
+            _ => None,
+        }?;
+
+        self.get_solid_tile(*neighbor)
+    }
+}
+
+/// Which side of the cell an edge lies on, if any
+fn get_edge_side(start: Vec2, end: Vec2) -> Option<TileSide> {
+    let on_line = |a: f32, b: f32, line: f32| {
+        (a - line).abs() < SIDE_EPSILON && (b - line).abs() < SIDE_EPSILON
+    };
+
+    if on_line(start.y, end.y, 0.0) {
+        Some(TileSide::Top)
+    } else if on_line(start.y, end.y, 1.0) {
+        Some(TileSide::Bottom)
+    } else if on_line(start.x, end.x, 0.0) {
+        Some(TileSide::Left)
+    } else if on_line(start.x, end.x, 1.0) {
+        Some(TileSide::Right)
+    } else {
+        None
+    }
+}
+
+/// The range an edge covers along a side, from smallest to largest
+fn get_side_interval(start: Vec2, end: Vec2, side: TileSide) -> (f32, f32) {
+    let (a, b) = match side {
+        TileSide::Top | TileSide::Bottom => (start.x, end.x),
+        TileSide::Left | TileSide::Right => (start.y, end.y),
+    };
+
+    (a.min(b), a.max(b))
+}
+
+/// The sorted ranges of one side of a cell that a tile covers
+fn get_side_coverage(tile_definition: &TileDefinition, side: TileSide) -> Vec<(f32, f32)> {
+    let outline = &tile_definition.outline;
+
+    let mut coverage: Vec<(f32, f32)> = (0..outline.len())
+        .map(|point_index| {
+            (
+                outline[point_index],
+                outline[(point_index + 1) % outline.len()],
+            )
+        })
+        .filter(|(start, end)| get_edge_side(*start, *end) == Some(side))
+        .map(|(start, end)| get_side_interval(start, end, side))
+        .collect();
+
+    coverage.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    coverage
+}
+
+fn push_side_edge(
+    cell_position: Vec2,
+    side: TileSide,
+    from: f32,
+    to: f32,
+    line_points: &mut Vec<Vec2>,
+) {
+    let (start, end) = match side {
+        TileSide::Top => (Vec2::new(from, 0.0), Vec2::new(to, 0.0)),
+        TileSide::Bottom => (Vec2::new(from, 1.0), Vec2::new(to, 1.0)),
+        TileSide::Left => (Vec2::new(0.0, from), Vec2::new(0.0, to)),
+        TileSide::Right => (Vec2::new(1.0, from), Vec2::new(1.0, to)),
+    };
+
+    line_points.push(cell_position + start);
+    line_points.push(cell_position + end);
+}
+
+#[derive(Default)]
+pub struct TilesetLoader;
+
+impl AssetLoader for TilesetLoader {
+    type Asset = Tileset;
+    type Settings = ();
+    type Error = LevelLoadError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Tileset, LevelLoadError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+
+            Tileset::from_bytes(&bytes)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["tileset.json"]
+    }
+}
@@ -5,20 +5,28 @@ mod utils;
 
 use ::bevy::prelude::*;
 use ai::{
+    a_star::get_reachable_nodes,
+    d_star_lite::PathPlanner,
     pathfinding,
-    platformer_ai::{PlatformerAI, PlatformerAIPlugin, PLATFORMER_AI_AGENT_RADIUS},
+    platformer_ai::{PlatformerAI, PlatformerAIPlugin, AGENT_PROFILES},
 };
 use bevy::{
     app::AppExit,
     window::{PresentMode, PrimaryWindow},
 };
 use collisions::{s_collision, CollisionPlugin};
-use level::{generate_level_polygons, Level, Polygon};
-use pathfinding::{init_pathfinding_graph, Pathfinding, PathfindingPlugin};
-use utils::line_intersect;
+use level::{
+    editor::LevelEditorPlugin,
+    loader::{LevelPlugin, LevelSourcePlugin},
+    Level,
+};
+use pathfinding::{Pathfinding, PathfindingPlugin};
 
 pub const GRAVITY_STRENGTH: f32 = 0.5;
 
+/// Where the agents start when the level doesn't have a spawn point
+pub const DEFAULT_SPAWN_POINT: Vec2 = Vec2::new(0.0, -250.0);
+
 fn main() {
     App::new()
         .insert_resource(ClearColor(Color::rgb(0.0, 0.0, 0.0)))
@@ -28,6 +36,7 @@ fn main() {
         //     position: Vec2::new(0.0, 0.0),
         //     enabled: false,
         // })
+        .add_plugins(LevelSourcePlugin)
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
                 title: "Platformer AI Test".to_string(),
@@ -37,15 +46,23 @@ fn main() {
             }),
             ..default()
         }))
+        .add_plugins(LevelPlugin)
+        .add_plugins(LevelEditorPlugin)
         .add_plugins(PathfindingPlugin)
         .add_plugins(PlatformerAIPlugin)
         .add_plugins(CollisionPlugin)
         // Startup systems
         .add_systems(Startup, s_init)
         // Update systems
+        .add_systems(Update, s_spawn_agents.run_if(resource_added::<Level>()))
         .add_systems(Update, s_input)
         .add_systems(Update, s_move_goal_point.after(s_input))
-        .add_systems(Update, s_render.after(s_collision))
+        .add_systems(
+            Update,
+            s_render
+                .after(s_collision)
+                .run_if(resource_exists::<Level>()),
+        )
         .run();
 }
 
@@ -71,44 +88,45 @@ pub struct Physics {
     pub has_wall_jumped: bool,
 }
 
-pub fn s_init(mut commands: Commands, pathfinding: ResMut<Pathfinding>) {
-    let grid_size = 32.0;
-
-    let (level_polygons, size, half_size) = generate_level_polygons(grid_size);
-
-    let level = Level {
-        polygons: level_polygons,
-        grid_size,
-        size,
-        half_size,
-    };
-
-    init_pathfinding_graph(&level, pathfinding);
-
-    commands.insert_resource(level);
-
+pub fn s_init(mut commands: Commands) {
     commands.spawn(Camera2dBundle::default());
+}
 
-    commands.spawn((
-        Transform::from_translation(Vec3::new(0.0, -250.0, 0.0)),
-        Physics {
-            prev_position: Vec2::ZERO,
-            velocity: Vec2::ZERO,
-            acceleration: Vec2::ZERO,
-            radius: PLATFORMER_AI_AGENT_RADIUS,
-            normal: Vec2::ZERO,
-            grounded: false,
-            walled: 0,
-            has_wall_jumped: false,
-        },
-        PlatformerAI {
-            current_target_node: None,
-            jump_from_pos: None,
-            jump_to_pos: None,
-        },
-    ));
+/// Spawns the agents once the first level is built, so they start at its spawn point
+pub fn s_spawn_agents(mut commands: Commands, level: Res<Level>) {
+    let spawn_point = level
+        .spawn_points
+        .first()
+        .copied()
+        .unwrap_or(DEFAULT_SPAWN_POINT);
+
+    // One agent of each kind
+    for (profile_index, profile) in AGENT_PROFILES.iter().enumerate() {
+        commands.spawn((
+            Transform::from_translation(spawn_point.extend(0.0)),
+            Physics {
+                prev_position: Vec2::ZERO,
+                velocity: Vec2::ZERO,
+                acceleration: Vec2::ZERO,
+                radius: profile.radius,
+                normal: Vec2::ZERO,
+                grounded: false,
+                walled: 0,
+                has_wall_jumped: false,
+            },
+            PlatformerAI {
+                profile: profile_index,
+                current_target_node: None,
+                jump_from_pos: None,
+                jump_to_pos: None,
+                shortcut_travel: None,
+                path_planner: PathPlanner::default(),
+            },
+        ));
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn s_input(
     keyboard_input: Res<Input<KeyCode>>,
     mut exit: EventWriter<AppExit>,
@@ -118,6 +136,7 @@ pub fn s_input(
     mouse_buttons: Res<Input<MouseButton>>,
     q_windows: Query<&Window, With<PrimaryWindow>>,
     mut pathfinding: ResMut<Pathfinding>,
+    level: Option<Res<Level>>,
 ) {
     // Escape to exit (if not WASM)
     #[cfg(not(target_arch = "wasm32"))]
@@ -127,8 +146,14 @@ pub fn s_input(
 
     // R to reset
     if keyboard_input.just_pressed(KeyCode::R) {
-        for (mut transform, mut physics, _platformer_ai) in platformer_ai_query.iter_mut() {
-            transform.translation = Vec3::new(0.0, -250.0, 0.0);
+        // Go back to the level's spawn point, if it has one
+        let spawn_point = level
+            .and_then(|level| level.spawn_points.first().copied())
+            .unwrap_or(DEFAULT_SPAWN_POINT);
+
+        for (mut transform, mut physics, mut platformer_ai) in platformer_ai_query.iter_mut() {
+            transform.translation = spawn_point.extend(0.0);
+            platformer_ai.shortcut_travel = None;
             physics.prev_position = Vec2::ZERO;
             physics.velocity = Vec2::ZERO;
             physics.acceleration = Vec2::ZERO;
@@ -167,23 +192,6 @@ pub fn s_input(
     // Space to toggle goal point
     if keyboard_input.just_pressed(KeyCode::Space) {
         pathfinding.active = !pathfinding.active;
-        if pathfinding.active {
-            // Set the closest node to the node closest to the goal point
-            let mut closest_distance = f32::MAX;
-
-            for node_index in 0..pathfinding.nodes.len() {
-                let node = &pathfinding.nodes[node_index];
-
-                let distance = (pathfinding.goal_position - node.position).length_squared();
-
-                if distance < closest_distance {
-                    closest_distance = distance;
-                    pathfinding.goal_graph_node = Some(node.clone());
-                }
-            }
-        } else {
-            pathfinding.goal_graph_node = None;
-        }
     }
 
     // Print some debug info if you click on a pathfinding node
@@ -195,12 +203,14 @@ pub fn s_input(
                 position - Vec2::new(window_size.width() / 2.0, window_size.height() / 2.0);
             mouse_pos_world.y *= -1.0;
 
-            for node_index in 0..pathfinding.nodes.len() {
-                let node = &pathfinding.nodes[node_index];
+            for (profile_index, graph) in pathfinding.graphs.iter().enumerate() {
+                for node_index in 0..graph.nodes.len() {
+                    let node = &graph.nodes[node_index];
 
-                if (mouse_pos_world - node.position).length_squared() < (3.5_f32).powi(2) {
-                    println!("Node index: {}", node_index);
-                    dbg!(node);
+                    if (mouse_pos_world - node.position).length_squared() < (3.5_f32).powi(2) {
+                        println!("Profile: {}, Node index: {}", profile_index, node_index);
+                        dbg!(node);
+                    }
                 }
             }
         }
@@ -209,21 +219,6 @@ pub fn s_input(
 
 pub fn s_move_goal_point(input_dir: Res<InputDir>, mut pathfinding: ResMut<Pathfinding>) {
     pathfinding.goal_position += input_dir.dir * 4.0;
-
-    if pathfinding.active {
-        // Set the closest node to the node closest to the goal point
-        let mut closest_distance = f32::MAX;
-        for node_index in 0..pathfinding.nodes.len() {
-            let node = &pathfinding.nodes[node_index];
-
-            let distance = (pathfinding.goal_position - node.position).length_squared();
-
-            if distance < closest_distance {
-                closest_distance = distance;
-                pathfinding.goal_graph_node = Some(node.clone());
-            }
-        }
-    }
 }
 
 pub fn s_render(
@@ -235,34 +230,65 @@ pub fn s_render(
 ) {
     // Draw the level polygons
     for polygon in &level.polygons {
-        gizmos.linestrip_2d(
-            polygon.points.iter().cloned().collect::<Vec<Vec2>>(),
-            polygon.color,
-        );
+        gizmos.linestrip_2d(polygon.points.to_vec(), polygon.color);
     }
 
-    if gizmos_visible.visible {
-        // Draw the pathfinding nodes
-        for node in &pathfinding.nodes {
-            gizmos.circle_2d(node.position, 2.5, Color::WHITE);
+    // Draw the shortcut pipes
+    for shortcut in &level.shortcuts {
+        gizmos.linestrip_2d(shortcut.entrances.to_vec(), Color::PURPLE.with_a(0.3));
+
+        for entrance in &shortcut.entrances {
+            gizmos.circle_2d(*entrance, 6.0, Color::PURPLE);
         }
+    }
+
+    if gizmos_visible.visible {
+        // Draw the pathfinding graph of every profile
+        for graph in &pathfinding.graphs {
+            // Draw the pathfinding nodes
+            for node in &graph.nodes {
+                gizmos.circle_2d(node.position, 2.5, Color::WHITE);
+            }
+
+            // Draw the pathfinding connections
+            for node in &graph.nodes {
+                for connection in &node.jumpable_connections {
+                    let connected_node = &graph.nodes[connection.node_id];
+
+                    gizmos.line_2d(
+                        node.position,
+                        connected_node.position,
+                        Color::WHITE.with_a(0.01),
+                    );
+                }
+
+                for connection in &node.wall_jumpable_connections {
+                    let connected_node = &graph.nodes[connection.node_id];
+
+                    gizmos.line_2d(
+                        node.position,
+                        connected_node.position,
+                        Color::CYAN.with_a(0.05),
+                    );
+                }
 
-        // Draw the pathfinding connections
-        for node in &pathfinding.nodes {
-            for connection in &node.jumpable_connections {
-                let connected_node = &pathfinding.nodes[connection.node_id];
+                for connection in &node.droppable_connections {
+                    let connected_node = &graph.nodes[connection.node_id];
 
-                gizmos.line_2d(
-                    node.position,
-                    connected_node.position,
-                    Color::WHITE.with_a(0.01),
-                );
+                    gizmos.line_2d(
+                        node.position,
+                        connected_node.position,
+                        Color::YELLOW.with_a(0.3),
+                    );
+                }
             }
-        }
 
-        // Draw the pathfinding closest node
-        if let Some(goal_node) = &pathfinding.goal_graph_node {
-            gizmos.circle_2d(goal_node.position, 5.0, Color::GREEN);
+            // Draw the nodes the goal point is connected to while pathfinding
+            if pathfinding.active {
+                for node_id in get_reachable_nodes(graph, &level, pathfinding.goal_position) {
+                    gizmos.circle_2d(graph.nodes[node_id].position, 5.0, Color::GREEN);
+                }
+            }
         }
     }
 
@@ -279,17 +305,41 @@ pub fn s_render(
 
     // Draw the platformer AI
     for (transform, physics, platformer_ai) in platformer_ai_query.iter() {
+        // Agents in a shortcut are hidden inside the pipe
+        if platformer_ai.shortcut_travel.is_some() {
+            continue;
+        }
+
         gizmos.circle_2d(transform.translation.xy(), physics.radius, Color::RED);
 
-        if gizmos_visible.visible
-            && platformer_ai.jump_from_pos.is_some()
-            && platformer_ai.jump_to_pos.is_some()
-        {
-            let jump_from_node = platformer_ai.jump_from_pos.unwrap();
-            let jump_to_node = platformer_ai.jump_to_pos.unwrap();
+        // Draw what the AI can see of the goal point
+        if gizmos_visible.visible {
+            let agent_position = transform.translation.xy();
+            let to_goal = pathfinding.goal_position - agent_position;
+
+            match level.raycast(agent_position, to_goal, to_goal.length()) {
+                Some(hit) => {
+                    gizmos.line_2d(agent_position, hit.point, Color::ORANGE.with_a(0.5));
+                    gizmos.line_2d(hit.point, hit.point + hit.normal * 10.0, Color::ORANGE);
+                }
+                None => {
+                    gizmos.line_2d(
+                        agent_position,
+                        pathfinding.goal_position,
+                        Color::GREEN.with_a(0.5),
+                    );
+                }
+            }
+        }
 
+        if let (true, Some(jump_from_node), Some(jump_to_node)) = (
+            gizmos_visible.visible,
+            platformer_ai.jump_from_pos,
+            platformer_ai.jump_to_pos,
+        ) {
             let delta_p = jump_to_node - jump_from_node;
-            let acceleration = Vec2::new(0.0, -GRAVITY_STRENGTH);
+            let gravity = pathfinding.graphs[platformer_ai.profile].profile.gravity;
+            let acceleration = Vec2::new(0.0, -gravity);
 
             let t = (4.0 * delta_p.dot(delta_p) / acceleration.dot(acceleration))
                 .sqrt()
@@ -297,7 +347,7 @@ pub fn s_render(
 
             let launch_velocity = delta_p / t - acceleration * t / 2.0;
 
-            let timestep = t / 10 as f32;
+            let timestep = t / 10_f32;
 
             let mut prev_pos = jump_from_node;
 